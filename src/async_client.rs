@@ -0,0 +1,194 @@
+//! Asynchronous client running the PAM transaction on a dedicated thread
+//!
+//! All PAM functions block (e.g. pam_unix delays failed authentications by about two
+//! seconds), so calling them from an async executor stalls it. An `AsyncClient` owns the
+//! PAM transaction on a dedicated OS thread and forwards the conversation to a `Prompts`
+//! stream, which the UI side can consume concurrently:
+//!
+//! ```no_run
+//! use std::ffi::CString;
+//! use pam::async_client::{AsyncClient, Prompts};
+//! use pam::PamMessageStyle;
+//!
+//! async fn answer(mut prompts: Prompts) {
+//!     while let Some(prompt) = prompts.next().await {
+//!         match prompt.style() {
+//!             PamMessageStyle::Prompt_Echo_On => prompt.answer(CString::new("login").unwrap()),
+//!             PamMessageStyle::Prompt_Echo_Off => prompt.answer(CString::new("pass").unwrap()),
+//!             _ => println!("{}", prompt.text().to_string_lossy()),
+//!         }
+//!     }
+//! }
+//!
+//! async fn login() -> pam::PamResult<()> {
+//!     let (mut client, prompts) = AsyncClient::new("system-auth").await?;
+//!     // Spawn `answer(prompts)` on your executor, then
+//!     client.authenticate().await?;
+//!     client.open_session().await
+//! }
+//! ```
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::mpsc,
+    task::{Context, Poll},
+    thread,
+};
+
+use futures_channel::{
+    mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use futures_core::Stream;
+
+pub use crate::channel::Prompt;
+
+use crate::{
+    client::{AccountStatus, Client},
+    ChannelConv, PamError, PamFlag, PamResult, PamReturnCode,
+};
+
+/// A PAM client running the transaction on a dedicated thread
+///
+/// The transaction is ended (according to the `DropPolicy` of the underlying `Client`)
+/// once the `AsyncClient` is dropped.
+pub struct AsyncClient {
+    requests: mpsc::Sender<Request>,
+}
+
+/// The messages of the conversation of an `AsyncClient`
+///
+/// Every message has to be handled via `Prompt::answer` or `Prompt::fail`. Dropping a
+/// prompt (or the `Prompts` themselves) aborts the conversation.
+pub struct Prompts {
+    rx: UnboundedReceiver<Prompt>,
+}
+
+enum Request {
+    Authenticate(oneshot::Sender<PamResult<()>>),
+    CheckAccount(oneshot::Sender<PamResult<AccountStatus>>),
+    OpenSession(oneshot::Sender<PamResult<()>>),
+    CloseSession(oneshot::Sender<PamResult<()>>),
+    ChangeAuthToken(PamFlag, oneshot::Sender<PamResult<()>>),
+}
+
+impl AsyncClient {
+    /// Start a PAM transaction for the given service on a new thread
+    pub async fn new(service: &str) -> PamResult<(AsyncClient, Prompts)> {
+        let service = service.to_owned();
+        let (prompt_tx, prompt_rx) = unbounded();
+        let (request_tx, request_rx) = mpsc::channel();
+        let (init_tx, init_rx) = oneshot::channel();
+
+        thread::Builder::new()
+            .name("pam".into())
+            .spawn(move || run(&service, prompt_tx, request_rx, init_tx))
+            .map_err(|_| PamError(PamReturnCode::System_Err))?;
+
+        init_rx
+            .await
+            .unwrap_or(Err(PamReturnCode::System_Err.into()))?;
+        Ok((
+            AsyncClient {
+                requests: request_tx,
+            },
+            Prompts { rx: prompt_rx },
+        ))
+    }
+
+    /// Perform authentication, see `Client::authenticate`
+    pub async fn authenticate(&mut self) -> PamResult<()> {
+        self.request(Request::Authenticate).await
+    }
+
+    /// Determine if the account is valid, see `Client::check_account`
+    pub async fn check_account(&mut self) -> PamResult<AccountStatus> {
+        self.request(Request::CheckAccount).await
+    }
+
+    /// Open a session, see `Client::open_session`
+    pub async fn open_session(&mut self) -> PamResult<()> {
+        self.request(Request::OpenSession).await
+    }
+
+    /// Close the open session, see `Client::close_session`
+    pub async fn close_session(&mut self) -> PamResult<()> {
+        self.request(Request::CloseSession).await
+    }
+
+    /// Change the authentication token, see `Client::change_authentication_token`
+    pub async fn change_authentication_token(&mut self, flags: PamFlag) -> PamResult<()> {
+        self.request(|tx| Request::ChangeAuthToken(flags, tx)).await
+    }
+
+    async fn request<T, F>(&mut self, request: F) -> PamResult<T>
+    where
+        F: FnOnce(oneshot::Sender<PamResult<T>>) -> Request,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.requests
+            .send(request(tx))
+            .map_err(|_| PamError(PamReturnCode::System_Err))?;
+        // The thread only drops the sender if it panicked
+        rx.await.unwrap_or(Err(PamReturnCode::System_Err.into()))
+    }
+}
+
+// Runs the PAM transaction until the `AsyncClient` is dropped
+fn run(
+    service: &str,
+    prompts: UnboundedSender<Prompt>,
+    requests: mpsc::Receiver<Request>,
+    init: oneshot::Sender<PamResult<()>>,
+) {
+    let mut client = match Client::with_conversation(service, ChannelConv::unbounded(prompts)) {
+        Ok(client) => client,
+        Err(err) => {
+            let _ = init.send(Err(err));
+            return;
+        }
+    };
+    if init.send(Ok(())).is_err() {
+        return;
+    }
+
+    for request in requests {
+        // The caller might have stopped waiting for the result, so ignore send errors
+        match request {
+            Request::Authenticate(tx) => drop(tx.send(client.authenticate())),
+            Request::CheckAccount(tx) => drop(tx.send(client.check_account())),
+            Request::OpenSession(tx) => drop(tx.send(client.open_session())),
+            Request::CloseSession(tx) => drop(tx.send(client.close_session())),
+            Request::ChangeAuthToken(flags, tx) => {
+                drop(tx.send(client.change_authentication_token(flags)))
+            }
+        }
+    }
+}
+
+impl Prompts {
+    /// Wait for the next message of the conversation
+    ///
+    /// Returns `None` once the `AsyncClient` has been dropped.
+    pub async fn next(&mut self) -> Option<Prompt> {
+        NextPrompt(&mut self.rx).await
+    }
+}
+
+impl Stream for Prompts {
+    type Item = Prompt;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Prompt>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+struct NextPrompt<'a>(&'a mut UnboundedReceiver<Prompt>);
+
+impl Future for NextPrompt<'_> {
+    type Output = Option<Prompt>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Prompt>> {
+        Pin::new(&mut *self.0).poll_next(cx)
+    }
+}
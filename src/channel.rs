@@ -0,0 +1,240 @@
+//! Conversation handler forwarding messages to another thread
+use std::{
+    ffi::{CStr, CString},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+#[cfg(feature = "async")]
+use futures_channel::mpsc::UnboundedSender;
+
+use crate::{
+    conv::{ConvError, Conversation},
+    PamMessageStyle,
+};
+
+type Reply = Result<CString, ConvError>;
+
+/// A conversation handler sending all messages over a channel
+///
+/// This allows running the PAM transaction (e.g. `Client::authenticate`) on a worker
+/// thread while another thread (e.g. a GUI) displays the messages and collects the
+/// answers:
+///
+/// ```no_run
+/// use std::{ffi::CString, thread};
+/// use pam::{ChannelConv, Client};
+///
+/// let (conv, prompts) = ChannelConv::new();
+/// let worker = thread::spawn(move || {
+///     let mut client = Client::with_conversation("system-auth", conv)?;
+///     client.authenticate()
+/// });
+///
+/// for prompt in prompts {
+///     if prompt.is_prompt() {
+///         prompt.answer(CString::new("answer from the UI").unwrap());
+///     }
+/// }
+/// worker.join().unwrap().expect("Authentication failed!");
+/// ```
+///
+/// If the `Receiver` or a `Prompt` is dropped before answering, the conversation is
+/// aborted with `ConvError::Abort`. If a timeout is set, a prompt which is not answered
+/// in time fails the conversation with `ConvError::Failure`.
+#[derive(Debug, Clone)]
+pub struct ChannelConv {
+    prompts: PromptSender,
+    timeout: Option<Duration>,
+}
+
+// The sending half of the channel, `AsyncClient` forwards its prompts to a `Stream`
+#[derive(Debug, Clone)]
+enum PromptSender {
+    Sync(Sender<Prompt>),
+    #[cfg(feature = "async")]
+    Async(UnboundedSender<Prompt>),
+}
+
+impl PromptSender {
+    // Fails if the receiver has been dropped
+    fn send(&self, prompt: Prompt) -> Result<(), ConvError> {
+        let sent = match self {
+            PromptSender::Sync(tx) => tx.send(prompt).is_ok(),
+            #[cfg(feature = "async")]
+            PromptSender::Async(tx) => tx.unbounded_send(prompt).is_ok(),
+        };
+        if sent {
+            Ok(())
+        } else {
+            Err(ConvError::Abort)
+        }
+    }
+}
+
+impl ChannelConv {
+    /// Create a new `ChannelConv` and the `Receiver` for its messages
+    pub fn new() -> (ChannelConv, Receiver<Prompt>) {
+        let (tx, rx) = mpsc::channel();
+        let conv = ChannelConv {
+            prompts: PromptSender::Sync(tx),
+            timeout: None,
+        };
+        (conv, rx)
+    }
+
+    // Create a `ChannelConv` forwarding its messages to an async `Stream`
+    #[cfg(feature = "async")]
+    pub(crate) fn unbounded(prompts: UnboundedSender<Prompt>) -> ChannelConv {
+        ChannelConv {
+            prompts: PromptSender::Async(prompts),
+            timeout: None,
+        }
+    }
+
+    /// Create a new `ChannelConv` which waits at most `timeout` for each answer
+    pub fn with_timeout(timeout: Duration) -> (ChannelConv, Receiver<Prompt>) {
+        let (mut conv, rx) = ChannelConv::new();
+        conv.set_timeout(Some(timeout));
+        (conv, rx)
+    }
+
+    /// Change the time to wait for each answer, `None` waits indefinitely
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn prompt(&mut self, style: PamMessageStyle, msg: &CStr) -> Result<CString, ConvError> {
+        let (prompt, reply) = Prompt::new(style, msg);
+        self.prompts.send(prompt)?;
+        let reply = reply.expect("prompts always expect a reply");
+        match self.timeout {
+            Some(timeout) => reply.recv_timeout(timeout).unwrap_or_else(|err| match err {
+                RecvTimeoutError::Timeout => Err(ConvError::Failure),
+                RecvTimeoutError::Disconnected => Err(ConvError::Abort),
+            }),
+            None => reply.recv().unwrap_or(Err(ConvError::Abort)),
+        }
+    }
+
+    fn message(&mut self, style: PamMessageStyle, msg: &CStr) {
+        // Nobody is listening anymore, the next prompt will abort the conversation
+        let _ = self.prompts.send(Prompt::new(style, msg).0);
+    }
+}
+
+impl Conversation for ChannelConv {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        self.prompt(PamMessageStyle::Prompt_Echo_On, msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        self.prompt(PamMessageStyle::Prompt_Echo_Off, msg)
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.message(PamMessageStyle::Text_Info, msg);
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.message(PamMessageStyle::Error_Msg, msg);
+    }
+}
+
+/// A message of a conversation forwarded to another thread
+///
+/// Prompts have to be handled via `Prompt::answer` or `Prompt::fail`, dropping them
+/// aborts the conversation.
+#[derive(Debug)]
+pub struct Prompt {
+    style: PamMessageStyle,
+    text: CString,
+    reply: Option<Sender<Reply>>,
+}
+
+impl Prompt {
+    // Create a message and, if PAM expects an answer, the receiver for it
+    pub(crate) fn new(style: PamMessageStyle, text: &CStr) -> (Prompt, Option<Receiver<Reply>>) {
+        let (reply, rx) = match style {
+            PamMessageStyle::Prompt_Echo_On | PamMessageStyle::Prompt_Echo_Off => {
+                let (tx, rx) = mpsc::channel();
+                (Some(tx), Some(rx))
+            }
+            _ => (None, None),
+        };
+        let prompt = Prompt {
+            style,
+            text: text.to_owned(),
+            reply,
+        };
+        (prompt, rx)
+    }
+
+    /// The style of the message, i.e. whether it is a prompt or informational
+    pub fn style(&self) -> PamMessageStyle {
+        self.style
+    }
+
+    /// The text of the message
+    pub fn text(&self) -> &CStr {
+        &self.text
+    }
+
+    /// Whether PAM expects an answer to this message
+    pub fn is_prompt(&self) -> bool {
+        self.reply.is_some()
+    }
+
+    /// Answer the prompt, this is a no-op for informational and error messages
+    pub fn answer(self, response: CString) {
+        self.reply(Ok(response));
+    }
+
+    /// Fail the conversation with the given error
+    pub fn fail(self, err: ConvError) {
+        self.reply(Err(err));
+    }
+
+    fn reply(self, response: Reply) {
+        if let Some(reply) = self.reply {
+            // The conversation may have timed out already
+            let _ = reply.send(response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn channel_conv_passes_messages_and_answers() {
+        let (mut conv, prompts) = ChannelConv::with_timeout(Duration::from_secs(5));
+        let ui = thread::spawn(move || {
+            for prompt in prompts {
+                match prompt.style() {
+                    PamMessageStyle::Prompt_Echo_On => prompt.answer(CString::new("user").unwrap()),
+                    PamMessageStyle::Prompt_Echo_Off => prompt.fail(ConvError::Abort),
+                    _ => assert!(!prompt.is_prompt()),
+                }
+            }
+        });
+
+        let msg = CString::new("msg").unwrap();
+        conv.info(&msg);
+        assert_eq!(conv.prompt_echo(&msg), Ok(CString::new("user").unwrap()));
+        assert_eq!(conv.prompt_blind(&msg), Err(ConvError::Abort));
+
+        drop(conv);
+        ui.join().unwrap();
+    }
+
+    #[test]
+    fn channel_conv_fails_on_timeout_and_aborts_without_receiver() {
+        let (mut conv, prompts) = ChannelConv::with_timeout(Duration::from_millis(10));
+        let msg = CString::new("msg").unwrap();
+        // Nobody answers the prompt, but the receiver is still alive
+        assert_eq!(conv.prompt_echo(&msg), Err(ConvError::Failure));
+        drop(prompts);
+        assert_eq!(conv.prompt_echo(&msg), Err(ConvError::Abort));
+    }
+}
@@ -1,7 +1,25 @@
 //! Authentication related structure and functions
-use std::{env, ffi::CStr, os::raw::c_char};
+use std::{
+    env,
+    ffi::{CStr, CString, OsStr, OsString},
+    io,
+    mem::{self, ManuallyDrop},
+    os::{
+        raw::{c_char, c_int, c_uint, c_void},
+        unix::{
+            ffi::OsStringExt,
+            process::{CommandExt, ExitStatusExt},
+        },
+    },
+    path::PathBuf,
+    process::{Command, ExitStatus},
+    sync::atomic::{AtomicI32, Ordering},
+    time::Duration,
+};
 
-use crate::{conv, enums::*, functions::*, types::*};
+use zeroize::Zeroizing;
+
+use crate::{conv, enums::*, env::PamEnvList, ffi, functions::*, types::*};
 
 /// Main struct to authenticate a user
 ///
@@ -22,118 +40,683 @@ use crate::{conv, enums::*, functions::*, types::*};
 /// client.open_session().expect("Failed to open a session!");
 /// ```
 ///
+/// `Client::open_session` checks whether the account is valid if this has not been done
+/// before. To handle an expired password, call `Client::check_account` explicitly:
+///
+/// ```no_run
+/// use pam::{AccountStatus, Client, PamFlag};
+///
+/// let mut client = Client::with_password("system-auth")
+///         .expect("Failed to init PAM client.");
+/// client.conversation_mut().set_credentials("login", "password");
+/// client.authenticate().expect("Authentication failed!");
+/// if client.check_account().expect("Account is not valid!") == AccountStatus::PasswordExpired {
+///     // The password has to be changed in the same transaction before opening a session
+///     client
+///         .change_authentication_token(PamFlag::Change_Expired_AuthTok)
+///         .expect("Failed to change the password!");
+/// }
+/// client.open_session().expect("Failed to open a session!");
+/// ```
+///
 /// If you wish to customise the PAM conversation function, you should rather create your
 /// client with `Client::with_handler`, providing a struct implementing the
 /// `conv::Conversation` trait. You can then mutably access your conversation handler using the
 /// `Client::handler_mut` method.
 ///
 /// By default, the `Client` will close any opened session when dropped. If you don't
-/// want this, you can change its `close_on_drop` field to `False`.
-pub struct Client<'a, C: conv::Conversation> {
-    /// Flag indicating whether the Client should close the session on drop
-    pub close_on_drop: bool,
-    handle: &'a mut PamHandle,
-    conversation: Box<C>,
+/// want this, you can change its `DropPolicy` via `Client::set_drop_policy`. To handle
+/// errors while closing the session and ending the transaction, call `Client::end` instead.
+///
+/// When a session is opened, the `Client` exports some common variables for the user
+/// into the PAM environment. Use `Client::set_env_policy` to change where they are written to.
+pub struct Client<C: conv::Conversation> {
+    // Ended explicitly in `teardown`
+    handle: ManuallyDrop<PamHandle>,
+    // Boxed, as PAM holds a pointer to it
+    data: Box<AppData<C>>,
     is_authenticated: bool,
+    is_auth_pending: bool,
+    account_status: Option<AccountStatus>,
     has_open_session: bool,
     last_code: PamReturnCode,
+    env_policy: EnvPolicy,
+    drop_policy: DropPolicy,
+    silent: bool,
+    disallow_null_authtok: bool,
+    has_ended: bool,
 }
 
-impl<'a> Client<'a, conv::PasswordConv> {
-    /// Create a new `Client` with the given service name and a password-based conversation
-    pub fn with_password(service: &str) -> PamResult<Client<'a, conv::PasswordConv>> {
-        Client::with_conversation(service, conv::PasswordConv::new())
+/// Progress of a resumable authentication via `Client::authenticate_resumable`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthProgress {
+    /// The user has been authenticated
+    Done,
+    /// The conversation returned `ConvError::Again`, so the stack could not complete yet.
+    /// Call `Client::authenticate_resumable` again once the response is available.
+    Pending,
+}
+
+/// Status of a valid account as returned by `Client::check_account`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountStatus {
+    /// The account is valid and a session may be opened
+    Valid,
+    /// The password of the user has expired. It has to be changed via
+    /// `Client::change_authentication_token` with `PamFlag::Change_Expired_AuthTok`
+    /// before a session may be opened.
+    PasswordExpired,
+}
+
+/// Policy describing where `Client::open_session` sets the common session variables
+/// (`USER`, `LOGNAME`, `HOME`, `PWD` and `SHELL`)
+///
+/// Note that modifying the environment of the current process is not thread-safe and
+/// overwrites the variables of the calling program, so it should only be used by
+/// single-threaded programs which replace themselves with the user's session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EnvPolicy {
+    /// Only set the variables in the PAM environment (default)
+    Pam,
+    /// Only set the variables in the environment of the current process
+    Process,
+    /// Set the variables in both the PAM and the process environment
+    Both,
+    /// Don't set any variables
+    None,
+}
+
+impl Default for EnvPolicy {
+    fn default() -> Self {
+        EnvPolicy::Pam
     }
 }
 
-impl<'a, C: conv::Conversation> Client<'a, C> {
-    /// Create a new `Client` with the given service name and conversation handler
-    pub fn with_conversation(service: &str, conversation: C) -> PamResult<Client<'a, C>> {
-        let mut conversation = Box::new(conversation);
-        let conv = conv::into_pam_conv(&mut *conversation);
+/// Policy describing what a `Client` does with the PAM transaction when it is dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DropPolicy {
+    /// Close an open session, delete the credentials and end the transaction (default)
+    CloseSession,
+    /// Keep an open session, but delete the credentials and end the transaction
+    KeepSession,
+    /// Only end the transaction with `PAM_DATA_SILENT` (see `Client::end_silent_in_child`)
+    Silent,
+}
 
-        let handle = start(service, None, &conv)?;
-        Ok(Client {
-            close_on_drop: true,
-            handle,
+impl Default for DropPolicy {
+    fn default() -> Self {
+        DropPolicy::CloseSession
+    }
+}
+
+/// Policy for `Client::authenticate_with_retries`
+///
+/// By default, authentication is attempted up to 3 times like login(1) does, also for
+/// unknown users (so attackers can't tell them apart from wrong passwords), and `PAM_USER`
+/// is cleared before each retry so the user name is prompted again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_tries: u32,
+    retry_user_unknown: bool,
+    reprompt_user: bool,
+}
+
+impl RetryPolicy {
+    /// Create a new policy allowing up to `max_tries` attempts
+    ///
+    /// At least one attempt is always made, so 0 is treated as 1.
+    pub fn new(max_tries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_tries: max_tries.max(1),
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Whether to retry if PAM returned `User_Unknown`
+    pub fn retry_user_unknown(mut self, retry: bool) -> RetryPolicy {
+        self.retry_user_unknown = retry;
+        self
+    }
+
+    /// Whether to clear `PAM_USER` before each retry, so modules prompt for it again
+    ///
+    /// Disable this if the user was passed to the client in advance.
+    pub fn reprompt_user(mut self, reprompt: bool) -> RetryPolicy {
+        self.reprompt_user = reprompt;
+        self
+    }
+
+    /// The maximum number of attempts
+    pub fn max_tries(&self) -> u32 {
+        self.max_tries
+    }
+
+    // Whether an attempt failing with `code` may be retried
+    fn is_retryable(&self, code: PamReturnCode) -> bool {
+        match code {
+            PamReturnCode::Auth_Err
+            | PamReturnCode::Cred_Insufficient
+            | PamReturnCode::Authinfo_Unavail => true,
+            PamReturnCode::User_Unknown => self.retry_user_unknown,
+            // Includes `MaxTries` and `Abort`, which must not be retried
+            _ => false,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_tries: 3,
+            retry_user_unknown: true,
+            reprompt_user: true,
+        }
+    }
+}
+
+/// Builder to configure a `Client` before starting the PAM transaction
+///
+/// ```no_run
+/// use pam::{Client, DropPolicy, PamItemType, TtyConv};
+///
+/// let client = Client::builder("login")
+///     .user("alice")
+///     .item(PamItemType::TTY, "/dev/tty1")
+///     .item(PamItemType::RHost, "localhost")
+///     .silent(true)
+///     .drop_policy(DropPolicy::KeepSession)
+///     .build(TtyConv::new())
+///     .expect("Failed to init PAM client.");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    service: String,
+    user: Option<String>,
+    confdir: Option<PathBuf>,
+    items: Vec<(PamItemType, String)>,
+    silent: bool,
+    disallow_null_authtok: bool,
+    env_policy: EnvPolicy,
+    drop_policy: DropPolicy,
+}
+
+impl ClientBuilder {
+    /// Create a new builder for a `Client` using the given service name
+    pub fn new(service: &str) -> ClientBuilder {
+        ClientBuilder {
+            service: service.to_owned(),
+            user: None,
+            confdir: None,
+            items: Vec::new(),
+            silent: false,
+            disallow_null_authtok: false,
+            env_policy: EnvPolicy::default(),
+            drop_policy: DropPolicy::default(),
+        }
+    }
+
+    /// Start the transaction for the given user, so modules don't have to prompt for it
+    pub fn user(mut self, user: &str) -> ClientBuilder {
+        self.user = Some(user.to_owned());
+        self
+    }
+
+    /// Read the service configuration from `confdir` instead of `/etc/pam.d`
+    ///
+    /// This requires Linux-PAM 1.4 or newer and the `confdir` feature, otherwise `build`
+    /// fails with `Service_Err`.
+    pub fn confdir<P: Into<PathBuf>>(mut self, confdir: P) -> ClientBuilder {
+        self.confdir = Some(confdir.into());
+        self
+    }
+
+    /// Set a string item (e.g. `PamItemType::TTY` or `PamItemType::RHost`) right after
+    /// starting the transaction
+    ///
+    /// Items are set in the order they were added. Only `Service`, `User`, `TTY`, `RHost`,
+    /// `RUser`, `User_Prompt` and `XDisplay` hold strings, any other item type makes
+    /// `build` fail with `Bad_Item`.
+    pub fn item(mut self, item_type: PamItemType, value: &str) -> ClientBuilder {
+        self.items.push((item_type, value.to_owned()));
+        self
+    }
+
+    /// Pass `PAM_SILENT` to all PAM functions, so modules don't send informational messages
+    pub fn silent(mut self, silent: bool) -> ClientBuilder {
+        self.silent = silent;
+        self
+    }
+
+    /// Pass `PAM_DISALLOW_NULL_AUTHTOK` when authenticating and checking the account, so
+    /// users without a password are rejected
+    pub fn disallow_null_authtok(mut self, disallow: bool) -> ClientBuilder {
+        self.disallow_null_authtok = disallow;
+        self
+    }
+
+    /// Set the initial `EnvPolicy` of the client
+    pub fn env_policy(mut self, policy: EnvPolicy) -> ClientBuilder {
+        self.env_policy = policy;
+        self
+    }
+
+    /// Set the initial `DropPolicy` of the client
+    pub fn drop_policy(mut self, policy: DropPolicy) -> ClientBuilder {
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Start the PAM transaction with the given conversation handler
+    ///
+    /// If any step fails, the transaction is ended again and the error is returned.
+    pub fn build<C: conv::Conversation>(self, conversation: C) -> PamResult<Client<C>> {
+        let mut data = Box::new(AppData {
             conversation,
+            fail_delay: None,
+            authtok: None,
+            old_authtok: None,
+            token_phase: TokenPhase::Idle,
+        });
+        let conv = conv::into_pam_conv(&mut *data);
+
+        let user = self.user.as_deref();
+        let mut handle = match self.confdir {
+            #[cfg(all(target_os = "linux", feature = "confdir"))]
+            Some(ref confdir) => start_confdir(&self.service, user, &conv, confdir)?,
+            #[cfg(not(all(target_os = "linux", feature = "confdir")))]
+            Some(_) => return Err(PamReturnCode::Service_Err.into()),
+            None => start(&self.service, user, &conv)?,
+        };
+        // Dropping `handle` ends the transaction if setting an item fails
+        for (item_type, value) in &self.items {
+            set_str_item(&mut handle, *item_type, value)?;
+        }
+
+        Ok(Client {
+            handle: ManuallyDrop::new(handle),
+            data,
             is_authenticated: false,
+            is_auth_pending: false,
+            account_status: None,
             has_open_session: false,
             last_code: PamReturnCode::Success,
+            env_policy: self.env_policy,
+            drop_policy: self.drop_policy,
+            silent: self.silent,
+            disallow_null_authtok: self.disallow_null_authtok,
+            has_ended: false,
         })
     }
+}
+
+impl Client<conv::PasswordConv> {
+    /// Create a `ClientBuilder` to configure a client for the given service
+    ///
+    /// The conversation handler is passed to `ClientBuilder::build`.
+    pub fn builder(service: &str) -> ClientBuilder {
+        ClientBuilder::new(service)
+    }
+
+    /// Create a new `Client` with the given service name and a password-based conversation
+    pub fn with_password(service: &str) -> PamResult<Client<conv::PasswordConv>> {
+        Client::with_conversation(service, conv::PasswordConv::new())
+    }
+}
+
+impl<C: conv::Conversation> Client<C> {
+    /// Create a new `Client` with the given service name and conversation handler
+    ///
+    /// Use `Client::builder` to configure the client further.
+    pub fn with_conversation(service: &str, conversation: C) -> PamResult<Client<C>> {
+        ClientBuilder::new(service).build(conversation)
+    }
 
     /// Immutable access to the conversation handler of this Client
     pub fn conversation(&self) -> &C {
-        &*self.conversation
+        &self.data.conversation
     }
 
     /// Mutable access to the conversation handler of this Client
     pub fn conversation_mut(&mut self) -> &mut C {
-        &mut *self.conversation
+        &mut self.data.conversation
+    }
+
+    /// Replace the conversation handler for the remaining transaction and return the old one
+    ///
+    /// This allows using a different handler e.g. for `change_authentication_token` than
+    /// for `authenticate`. To switch between handlers of different types, create the
+    /// client with a `Box<dyn Conversation>`.
+    pub fn set_conversation(&mut self, conversation: C) -> C {
+        // PAM only references the boxed `AppData`, which stays in place
+        mem::replace(&mut self.data.conversation, conversation)
+    }
+
+    /// Handle failure delays requested by modules via `callback` instead of sleeping
+    ///
+    /// Modules like pam_unix request a delay of about two seconds after a failed attempt,
+    /// which libpam implements by sleeping at the end of e.g. `authenticate`. Once a
+    /// callback is registered (via `PAM_FAIL_DELAY`), libpam calls it with the return code
+    /// and the requested delay instead, so the application can enforce the delay itself,
+    /// e.g. asynchronously or by rejecting further attempts for that time.
+    ///
+    /// ```no_run
+    /// use pam::Client;
+    ///
+    /// let mut client = Client::with_password("system-auth").unwrap();
+    /// client
+    ///     .set_fail_delay(|status, delay| println!("{}: retry after {:?}", status, delay))
+    ///     .expect("Failed to register fail delay callback.");
+    /// ```
+    pub fn set_fail_delay<F>(&mut self, callback: F) -> PamResult<()>
+    where
+        F: FnMut(PamReturnCode, Duration) + Send + 'static,
+    {
+        let delay_fn: FailDelayFnPtr = fail_delay::<C>;
+        set_item(&mut self.handle, PamItemType::Fail_Delay, unsafe {
+            &*(delay_fn as *const c_void)
+        })?;
+        self.data.fail_delay = Some(Box::new(callback));
+        Ok(())
+    }
+
+    /// Preset the authentication token (`PAM_AUTHTOK`), e.g. a password the application
+    /// already received from the user
+    ///
+    /// Modules configured with `use_first_pass` or `try_first_pass` then use it without
+    /// prompting. PAM keeps its own copy, so `secret` is zeroized when it is dropped.
+    ///
+    /// Linux-PAM only allows modules to set the token. In that case (or if a module prompts
+    /// nevertheless), the token answers the blind prompts of the next `authenticate` or
+    /// `change_authentication_token` and is discarded once that finished. While changing
+    /// the password, it answers the prompts for the new password, but not those for the
+    /// current one (see `set_old_authtok`). Other prompts are still passed to the
+    /// conversation handler.
+    ///
+    /// ```no_run
+    /// use pam::{Client, Zeroizing};
+    ///
+    /// let mut client = Client::with_password("imap").unwrap();
+    /// client.conversation_mut().set_credentials("alice", "");
+    /// client.set_authtok(Zeroizing::new(String::from("secret"))).unwrap();
+    /// client.authenticate().expect("Authentication failed!");
+    /// ```
+    pub fn set_authtok(&mut self, secret: Zeroizing<String>) -> PamResult<()> {
+        self.set_secret_item(PamItemType::AuthTok, secret)
+    }
+
+    /// Preset the old authentication token (`PAM_OLDAUTHTOK`) for
+    /// `change_authentication_token`
+    ///
+    /// If PAM does not allow the application to set it, the token answers the blind
+    /// prompts of the next `change_authentication_token` asking for the current or old
+    /// password, like `set_authtok`.
+    pub fn set_old_authtok(&mut self, secret: Zeroizing<String>) -> PamResult<()> {
+        self.set_secret_item(PamItemType::OldAuthTok, secret)
+    }
+
+    /// The policy used to set the common session variables on `open_session`
+    pub fn env_policy(&self) -> EnvPolicy {
+        self.env_policy
+    }
+
+    /// Change where the common session variables are set on `open_session`
+    pub fn set_env_policy(&mut self, policy: EnvPolicy) {
+        self.env_policy = policy;
+    }
+
+    /// The policy used to clean up the PAM transaction when this Client is dropped
+    pub fn drop_policy(&self) -> DropPolicy {
+        self.drop_policy
+    }
+
+    /// Change how the PAM transaction is cleaned up when this Client is dropped
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.drop_policy = policy;
     }
 
     /// Perform authentication with the provided credentials
+    ///
+    /// This only runs the auth stack of the service. Whether the account is valid is
+    /// determined by `Client::check_account` (or implicitly by `Client::open_session`).
+    ///
+    /// If the conversation returns `ConvError::Again`, this fails with `Incomplete`.
+    /// Use `Client::authenticate_resumable` to support event-driven conversations.
     pub fn authenticate(&mut self) -> PamResult<()> {
-        self.last_code = authenticate(self.handle, PamFlag::None);
-        if self.last_code != PamReturnCode::Success {
-            // No need to reset here
-            return Err(From::from(self.last_code));
+        match self.authenticate_resumable()? {
+            AuthProgress::Done => Ok(()),
+            AuthProgress::Pending => Err(PamReturnCode::Incomplete.into()),
         }
+    }
 
-        self.is_authenticated = true;
+    /// Perform authentication, retrying failed attempts according to `policy`
+    ///
+    /// Attempts are repeated until authentication succeeds, `policy.max_tries()` attempts
+    /// failed or an error occurred which must not be retried (e.g. `MaxTries` or `Abort`).
+    /// Before each retry, `before_retry` is called with the conversation handler, the
+    /// number of failed attempts and the error, e.g. to display "Login incorrect". Tokens
+    /// preset via `Client::set_authtok` which are used to answer prompts are discarded
+    /// before retrying, as they were apparently wrong.
+    ///
+    /// ```no_run
+    /// use std::ffi::CString;
+    /// use pam::{Client, Conversation, RetryPolicy, TtyConv};
+    ///
+    /// let mut client = Client::with_conversation("login", TtyConv::new()).unwrap();
+    /// let incorrect = CString::new("Login incorrect").unwrap();
+    /// client
+    ///     .authenticate_with_retries(RetryPolicy::default(), |conv, _, _| conv.error(&incorrect))
+    ///     .expect("Authentication failed!");
+    /// ```
+    pub fn authenticate_with_retries<F>(
+        &mut self,
+        policy: RetryPolicy,
+        mut before_retry: F,
+    ) -> PamResult<()>
+    where
+        F: FnMut(&mut C, u32, &PamError),
+    {
+        let mut failed = 0;
+        loop {
+            let err = match self.authenticate() {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            failed += 1;
+            if failed >= policy.max_tries || !policy.is_retryable(err.0) {
+                return Err(err);
+            }
 
-        self.last_code = acct_mgmt(self.handle, PamFlag::None);
-        if self.last_code != PamReturnCode::Success {
-            // Probably not strictly neccessary but better be sure
-            return self.reset();
+            if policy.reprompt_user {
+                clear_item(&mut self.handle, PamItemType::User)?;
+            }
+            before_retry(&mut self.data.conversation, failed, &err);
         }
+    }
+
+    /// Perform or resume authentication for event-driven conversations
+    ///
+    /// If the conversation handler cannot provide a response immediately, it can return
+    /// `ConvError::Again`. The auth stack is then suspended and this returns
+    /// `AuthProgress::Pending`. Once the response is available, calling this method again
+    /// resumes the stack where it stopped:
+    ///
+    /// ```no_run
+    /// use std::ffi::{CStr, CString};
+    /// use pam::{AuthProgress, Client, ConvError, Conversation};
+    ///
+    /// // A handler answering with the response provided by the UI, if there is one yet
+    /// struct UiConv(Option<CString>);
+    /// impl Conversation for UiConv {
+    ///     fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ConvError> {
+    ///         self.0.take().ok_or(ConvError::Again)
+    ///     }
+    ///     fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ConvError> {
+    ///         self.0.take().ok_or(ConvError::Again)
+    ///     }
+    ///     fn info(&mut self, _msg: &CStr) {}
+    ///     fn error(&mut self, _msg: &CStr) {}
+    /// }
+    ///
+    /// let mut client = Client::with_conversation("system-auth", UiConv(None))
+    ///     .expect("Failed to init PAM client.");
+    /// let mut progress = client.authenticate_resumable().expect("Authentication failed!");
+    /// while progress == AuthProgress::Pending {
+    ///     // Once the user answered the prompt, pass on the response and resume
+    ///     client.conversation_mut().0 = Some(CString::new("response").unwrap());
+    ///     progress = client.authenticate_resumable().expect("Authentication failed!");
+    /// }
+    /// ```
+    pub fn authenticate_resumable(&mut self) -> PamResult<AuthProgress> {
+        self.data.token_phase = TokenPhase::Authenticate;
+        self.last_code = self.call(ffi::pam_authenticate, self.auth_flags());
+        self.data.token_phase = TokenPhase::Idle;
+        if !matches!(
+            self.last_code,
+            PamReturnCode::Incomplete | PamReturnCode::Conv_Again
+        ) {
+            self.data.authtok = None;
+        }
+        match self.last_code {
+            PamReturnCode::Success => {
+                self.is_auth_pending = false;
+                self.is_authenticated = true;
+                self.account_status = None;
+                Ok(AuthProgress::Done)
+            }
+            PamReturnCode::Incomplete | PamReturnCode::Conv_Again => {
+                self.is_auth_pending = true;
+                Ok(AuthProgress::Pending)
+            }
+            code => {
+                // No need to reset here
+                self.is_auth_pending = false;
+                Err(From::from(code))
+            }
+        }
+    }
+
+    /// Whether an authentication started via `Client::authenticate_resumable` is suspended
+    pub fn is_auth_pending(&self) -> bool {
+        self.is_auth_pending
+    }
+
+    /// Skip the auth stack and treat `user` as authenticated
+    ///
+    /// This is meant for services which authenticate users by other means (e.g. public keys)
+    /// and only need PAM for account management and sessions. It sets `PAM_USER`, so that
+    /// `Client::check_account` and `Client::open_session` can run the account and session
+    /// stacks afterwards:
+    ///
+    /// ```no_run
+    /// use pam::Client;
+    ///
+    /// let mut client = Client::with_password("sshd").expect("Failed to init PAM client.");
+    /// // The user has already been authenticated via their public key
+    /// client.assume_authenticated_for("login").expect("Failed to set the user!");
+    /// client.check_account().expect("Account is not valid!");
+    /// client.open_session().expect("Failed to open a session!");
+    /// ```
+    ///
+    /// The modules cannot verify that the user was actually authenticated, so this must
+    /// only be called after the application authenticated the user itself.
+    pub fn assume_authenticated_for(&mut self, user: &str) -> PamResult<()> {
+        set_str_item(&mut self.handle, PamItemType::User, user)?;
+        self.is_authenticated = true;
+        self.account_status = None;
         Ok(())
     }
 
+    /// Determine if the account of the authenticated user is valid
+    ///
+    /// An expired password is reported as `AccountStatus::PasswordExpired`, so the caller
+    /// can offer the user to change it. All other failures are returned as errors.
+    pub fn check_account(&mut self) -> PamResult<AccountStatus> {
+        if !self.is_authenticated {
+            return Err(PamReturnCode::Perm_Denied.into());
+        }
+
+        self.last_code = self.call(ffi::pam_acct_mgmt, self.auth_flags());
+        let status = match self.last_code {
+            PamReturnCode::Success => AccountStatus::Valid,
+            PamReturnCode::New_Authtok_Reqd => AccountStatus::PasswordExpired,
+            // Probably not strictly neccessary but better be sure
+            _ => return self.reset(),
+        };
+        self.account_status = Some(status);
+        Ok(status)
+    }
+
     /// Perform the chauthtok to support password update
+    ///
+    /// If `Client::check_account` reported an expired password, successfully changing it
+    /// makes the account valid again.
     pub fn change_authentication_token(&mut self, flags: PamFlag) -> PamResult<()> {
-        self.last_code = chauthtok(self.handle, flags);
+        self.data.token_phase = TokenPhase::ChangeAuthtok;
+        self.last_code = self.call(ffi::pam_chauthtok, self.flags(flags));
+        self.data.token_phase = TokenPhase::Idle;
+        self.data.authtok = None;
+        self.data.old_authtok = None;
         if self.last_code != PamReturnCode::Success {
             // No need to reset here
             return Err(From::from(self.last_code));
         }
+
+        if self.account_status == Some(AccountStatus::PasswordExpired) {
+            self.account_status = Some(AccountStatus::Valid);
+        }
         Ok(())
     }
 
     /// Perform the get_item / PAM_USER to retrive the username
     pub fn get_user(&mut self) -> PamResult<String> {
-        get_item(self.handle, PamItemType::User).and_then(|result| {
+        self.get_user_os()?
+            .into_string()
+            .map_err(|_| PamError(PamReturnCode::System_Err))
+    }
+
+    // Retrieve PAM_USER without requiring it to be valid UTF-8
+    fn get_user_os(&self) -> PamResult<OsString> {
+        get_item(&self.handle, PamItemType::User).map(|result| {
             // Pam user is a char *
-            let ptr: *const c_char = unsafe { std::mem::transmute(result) };
+            let ptr = result as *const c_void as *const c_char;
             let username = unsafe { CStr::from_ptr(ptr) };
-            match username.to_str() {
-                Err(_) => Err(PamError(PamReturnCode::System_Err)),
-                Ok(username) => Ok(username.to_string()),
-            }
+            OsString::from_vec(username.to_bytes().to_vec())
         })
     }
 
     /// Open a session for a previously authenticated user and
-    /// initialize the environment appropriately (in PAM and regular enviroment variables).
+    /// initialize the environment appropriately (according to the `EnvPolicy` of this Client).
+    ///
+    /// If the account has not been checked yet, `Client::check_account` is called first.
+    /// An expired password which has not been changed results in `New_Authtok_Reqd`.
     pub fn open_session(&mut self) -> PamResult<()> {
         if !self.is_authenticated {
             //TODO: is this the right return code?
             return Err(PamReturnCode::Perm_Denied.into());
         }
 
-        self.last_code = setcred(self.handle, PamFlag::Establish_Cred);
+        let status = match self.account_status {
+            Some(status) => status,
+            None => self.check_account()?,
+        };
+        if status == AccountStatus::PasswordExpired {
+            return Err(PamReturnCode::New_Authtok_Reqd.into());
+        }
+
+        self.last_code = self.call(ffi::pam_setcred, self.flags(PamFlag::Establish_Cred));
         if self.last_code != PamReturnCode::Success {
             return self.reset();
         }
 
-        self.last_code = open_session(self.handle, false);
+        self.last_code = open_session(&mut self.handle, self.silent);
         if self.last_code != PamReturnCode::Success {
             return self.reset();
         }
 
         // Follow openSSH and call pam_setcred before and after open_session
-        self.last_code = setcred(self.handle, PamFlag::Reinitialize_Cred);
+        self.last_code = self.call(ffi::pam_setcred, self.flags(PamFlag::Reinitialize_Cred));
         if self.last_code != PamReturnCode::Success {
             return self.reset();
         }
@@ -142,67 +725,605 @@ impl<'a, C: conv::Conversation> Client<'a, C> {
         self.initialize_environment()
     }
 
+    /// Retrieve a copy of the complete PAM environment
+    ///
+    /// After `open_session` this contains the variables set by the session
+    /// modules (e.g. `XDG_RUNTIME_DIR` from pam_systemd or `KRB5CCNAME` from pam_krb5).
+    pub fn environment(&mut self) -> PamEnvList {
+        getenvlist(&mut self.handle)
+    }
+
+    /// Apply the PAM environment to `command`
+    ///
+    /// If `clear` is set, the environment inherited from the current process is
+    /// removed first, so the child process only sees the variables of the PAM session.
+    pub fn apply_environment(&mut self, command: &mut Command, clear: bool) {
+        if clear {
+            command.env_clear();
+        }
+        self.environment().apply_to(command);
+    }
+
+    /// Run `command` as the user of the open session and close the session once it exits
+    ///
+    /// This implements the lifecycle of login(1): The process forks and the child sets the
+    /// supplementary groups (via `initgroups`), gid and uid of the user, changes into their
+    /// home directory (unless `command` already has a working directory), applies the
+    /// PAM environment and executes `command`. The parent forwards SIGHUP and SIGTERM to the
+    /// child and waits for it to exit, ignoring SIGINT and SIGQUIT (the child receives them
+    /// from the terminal itself). Afterwards the session is closed and the credentials are
+    /// deleted.
+    ///
+    /// If the child should not inherit the environment of the current process, call
+    /// `Command::env_clear` before passing `command`.
+    ///
+    /// If the child cannot switch to the user, this fails with `Cred_Err`. If `command` cannot
+    /// be executed, it fails with `Perm_Denied` (e.g. missing permissions) or `System_Err`
+    /// (e.g. the program does not exist). In both cases the session is closed as well.
+    ///
+    /// Switching the user requires appropriate privileges (usually root). As the child calls
+    /// non async-signal-safe functions like `initgroups` before executing `command`, this
+    /// should only be called from single-threaded programs (like login(1) or su(1)).
+    pub fn run_session(&mut self, mut command: Command) -> PamResult<ExitStatus> {
+        use uzers::os::unix::UserExt;
+
+        if !self.has_open_session {
+            return Err(PamReturnCode::Perm_Denied.into());
+        }
+
+        // Prepare everything before forking, so the child only has to switch users
+        let name = self.get_user_os()?;
+        let user = uzers::get_user_by_name(&name).ok_or(PamError(PamReturnCode::User_Unknown))?;
+        let c_name = CString::new(name.into_vec()).map_err(|_| PamError(PamReturnCode::Buf_Err))?;
+        let (uid, gid) = (user.uid(), user.primary_group_id());
+
+        self.apply_environment(&mut command, false);
+        if command.get_current_dir().is_none() {
+            command.current_dir(user.home_dir());
+        }
+
+        // The child reports failures before executing `command` through this pipe
+        let (read_fd, write_fd) =
+            cloexec_pipe().map_err(|_| PamError(PamReturnCode::System_Err))?;
+        // Block the session signals until the parent installed its handlers, so they
+        // can't kill it while the session is still open
+        let old_mask = block_session_signals();
+
+        let pid = unsafe { libc::fork() };
+        if pid == -1 {
+            unsafe {
+                libc::pthread_sigmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut());
+                libc::close(read_fd);
+                libc::close(write_fd);
+            }
+            return Err(PamReturnCode::System_Err.into());
+        }
+        if pid == 0 {
+            // Child: drop privileges and execute the command
+            unsafe {
+                libc::close(read_fd);
+                libc::pthread_sigmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut());
+                if libc::initgroups(c_name.as_ptr(), gid as _) != 0
+                    || libc::setgid(gid) != 0
+                    || libc::setuid(uid) != 0
+                {
+                    let errno = io::Error::last_os_error().raw_os_error();
+                    report_child_error(write_fd, ChildError::Credentials, errno);
+                }
+            }
+            // exec only returns on error
+            let err = command.exec();
+            report_child_error(write_fd, ChildError::Exec, err.raw_os_error());
+        }
+        unsafe { libc::close(write_fd) };
+
+        // Parent: wait for the child while forwarding signals to it
+        let status = forward_signals_and_wait(pid, &old_mask);
+        // The pipe was closed by `exec` unless the child reported an error
+        let child_error = read_child_error(read_fd);
+        unsafe { libc::close(read_fd) };
+
+        let closed = self.close_session();
+        let status = status.map_err(|_| PamError(PamReturnCode::System_Err))?;
+        if let Some(code) = child_error {
+            return Err(code.into());
+        }
+        closed.map(|_| ExitStatus::from_raw(status))
+    }
+
+    /// Close the open session and delete the credentials of the user
+    ///
+    /// Afterwards the user has to be authenticated again to open another session.
+    pub fn close_session(&mut self) -> PamResult<()> {
+        if !self.has_open_session {
+            return Err(PamReturnCode::Session_Err.into());
+        }
+
+        self.last_code = close_session(&mut self.handle, self.silent);
+        self.has_open_session = false;
+        self.call(ffi::pam_setcred, self.flags(PamFlag::Delete_Cred));
+        self.is_authenticated = false;
+        self.account_status = None;
+
+        if self.last_code != PamReturnCode::Success {
+            return Err(self.last_code.into());
+        }
+        Ok(())
+    }
+
+    /// Close an open session, delete the credentials and end the PAM transaction
+    ///
+    /// Unlike dropping the Client, this reports errors from closing the session
+    /// and ending the transaction.
+    pub fn end(mut self) -> PamResult<()> {
+        self.teardown(DropPolicy::CloseSession)
+    }
+
+    /// End the PAM transaction in a forked child process
+    ///
+    /// This passes `PAM_DATA_SILENT` to `pam_end`, so the modules only release the memory
+    /// of the transaction without tearing down resources shared with the parent process
+    /// (like the open session). It neither closes the session nor deletes the credentials.
+    pub fn end_silent_in_child(mut self) -> PamResult<()> {
+        self.teardown(DropPolicy::Silent)
+    }
+
     // Initialize the client environment with common variables.
     // Currently always called from Client.open_session()
     fn initialize_environment(&mut self) -> PamResult<()> {
         use uzers::os::unix::UserExt;
 
-        let user = uzers::get_user_by_name(&self.get_user()?).unwrap_or_else(|| {
-            panic!(
-                "Could not get user by name: {:?}",
-                self.get_user()
-            )
-        });
+        let name = self.get_user_os()?;
 
         // Set some common environment variables
-        self.set_env(
-            "USER",
-            user.name()
-                .to_str()
-                .expect("Unix usernames should be valid UTF-8"),
-        )?;
-        self.set_env(
-            "LOGNAME",
-            user.name()
-                .to_str()
-                .expect("Unix usernames should be valid UTF-8"),
-        )?;
-        self.set_env("HOME", user.home_dir().to_str().unwrap())?;
-        self.set_env("PWD", user.home_dir().to_str().unwrap())?;
-        self.set_env("SHELL", user.shell().to_str().unwrap())?;
+        self.set_env("USER", &name)?;
+        self.set_env("LOGNAME", &name)?;
+
+        // Users provided by a PAM module might not be known to NSS,
+        // so only set the remaining variables if we find an entry
+        if let Some(user) = uzers::get_user_by_name(&name) {
+            self.set_env("HOME", user.home_dir().as_os_str())?;
+            self.set_env("PWD", user.home_dir().as_os_str())?;
+            self.set_env("SHELL", user.shell().as_os_str())?;
+        }
         // Note: We don't set PATH here, as this should be the job of `pam_env.so`
 
         Ok(())
     }
 
-    // Utility function to set an environment variable in PAM and the process
-    fn set_env(&mut self, key: &str, value: &str) -> PamResult<()> {
+    // Utility function to set an environment variable in PAM and/or the process
+    fn set_env(&mut self, key: &str, value: &OsStr) -> PamResult<()> {
         // Set regular environment variable
-        env::set_var(key, value);
+        if let EnvPolicy::Process | EnvPolicy::Both = self.env_policy {
+            env::set_var(key, value);
+        }
 
         // Set pam environment variable
-        if getenv(self.handle, key).is_ok() {
-            let name_value = format!("{}={}", key, value);
-            putenv(self.handle, &name_value)
-        } else {
-            Ok(())
+        if let EnvPolicy::Pam | EnvPolicy::Both = self.env_policy {
+            let mut name_value = OsString::from(key);
+            name_value.push("=");
+            name_value.push(value);
+            putenv(&mut self.handle, name_value)?;
+        }
+        Ok(())
+    }
+
+    // Utility function to end the PAM transaction according to `policy`
+    fn teardown(&mut self, policy: DropPolicy) -> PamResult<()> {
+        self.has_ended = true;
+
+        let mut result = Ok(());
+        let mut status = self.last_code;
+        if policy != DropPolicy::Silent {
+            if self.has_open_session && policy == DropPolicy::CloseSession {
+                let code = close_session(&mut self.handle, self.silent);
+                if code != PamReturnCode::Success {
+                    result = Err(code.into());
+                }
+                self.has_open_session = false;
+            }
+            status = self.call(ffi::pam_setcred, self.flags(PamFlag::Delete_Cred));
+        }
+
+        // `has_ended` guarantees the handle is not used afterwards
+        let handle = unsafe { ManuallyDrop::take(&mut self.handle) };
+        let code = end(handle, status, policy == DropPolicy::Silent);
+        if code != PamReturnCode::Success && result.is_ok() {
+            result = Err(code.into());
+        }
+        result
+    }
+
+    // Set a token item, falling back to answering prompts with it if PAM refuses
+    fn set_secret_item(
+        &mut self,
+        item_type: PamItemType,
+        secret: Zeroizing<String>,
+    ) -> PamResult<()> {
+        if secret.as_bytes().contains(&0) {
+            return Err(PamReturnCode::Buf_Err.into());
+        }
+        // Reserve the nul byte up front, so the secret is never copied by reallocating
+        let mut value = Zeroizing::new(Vec::with_capacity(secret.len() + 1));
+        value.extend_from_slice(secret.as_bytes());
+        value.push(0);
+
+        match set_item(&mut self.handle, item_type, unsafe {
+            &*(value.as_ptr() as *const c_void)
+        }) {
+            // Linux-PAM only lets modules set the tokens
+            Err(PamError(PamReturnCode::Bad_Item)) => {
+                if item_type == PamItemType::OldAuthTok {
+                    self.data.old_authtok = Some(secret);
+                } else {
+                    self.data.authtok = Some(secret);
+                }
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    // Combine `flag` with the default flags, as `PamFlag` can't represent ORed values
+    fn flags(&self, flag: PamFlag) -> c_int {
+        let mut flags = flag as c_int;
+        if self.silent {
+            flags |= PamFlag::Silent as c_int;
         }
+        flags
+    }
+
+    // Flags for `pam_authenticate` and `pam_acct_mgmt`
+    fn auth_flags(&self) -> c_int {
+        let mut flags = self.flags(PamFlag::None);
+        if self.disallow_null_authtok {
+            flags |= PamFlag::Disallow_Null_AuthTok as c_int;
+        }
+        flags
+    }
+
+    // Utility function to call a PAM function taking (combined) flags. The wrappers in
+    // `functions` take a single `PamFlag`, which can't represent e.g. `Silent` combined
+    // with `Disallow_Null_AuthTok`, so this calls PAM directly.
+    fn call(
+        &mut self,
+        f: unsafe extern "C" fn(*mut ffi::pam_handle_t, c_int) -> c_int,
+        flags: c_int,
+    ) -> PamReturnCode {
+        unsafe { f(self.handle.as_ptr(), flags) }.into()
     }
 
     // Utility function to reset the pam handle in case of intermediate errors
-    fn reset(&mut self) -> PamResult<()> {
-        setcred(self.handle, PamFlag::Delete_Cred);
+    fn reset<T>(&mut self) -> PamResult<T> {
+        self.call(ffi::pam_setcred, self.flags(PamFlag::Delete_Cred));
         self.is_authenticated = false;
+        self.account_status = None;
         Err(From::from(self.last_code))
     }
 }
 
-impl<'a, C: conv::Conversation> Drop for Client<'a, C> {
+// Pid of the child started by `Client::run_session`, which signals are forwarded to
+static SESSION_CHILD: AtomicI32 = AtomicI32::new(0);
+
+// The signals handled while waiting for the child and whether they are forwarded to it.
+// Like login(1), SIGINT and SIGQUIT are ignored, as the child is in the foreground process
+// group and already receives them from the terminal.
+const SESSION_SIGNALS: [(c_int, bool); 4] = [
+    (libc::SIGHUP, true),
+    (libc::SIGINT, false),
+    (libc::SIGQUIT, false),
+    (libc::SIGTERM, true),
+];
+
+extern "C" fn forward_signal(signal: c_int) {
+    let pid = SESSION_CHILD.load(Ordering::SeqCst);
+    if pid > 0 {
+        unsafe { libc::kill(pid, signal) };
+    }
+}
+
+// Wait for the child `pid` to exit and return its raw wait status
+fn forward_signals_and_wait(pid: libc::pid_t, old_mask: &libc::sigset_t) -> io::Result<c_int> {
+    SESSION_CHILD.store(pid, Ordering::SeqCst);
+
+    // Install our handlers and remember the previous ones
+    let mut previous: [libc::sigaction; 4] = unsafe { std::mem::zeroed() };
+    for (&(signal, forward), previous) in SESSION_SIGNALS.iter().zip(previous.iter_mut()) {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = if forward {
+                forward_signal as extern "C" fn(c_int) as libc::sighandler_t
+            } else {
+                libc::SIG_IGN
+            };
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, previous);
+        }
+    }
+    // Signals received since forking are delivered (and forwarded) now
+    unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, old_mask, std::ptr::null_mut()) };
+
+    let mut status = 0;
+    let result = loop {
+        if unsafe { libc::waitpid(pid, &mut status, 0) } == pid {
+            break Ok(status);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            break Err(err);
+        }
+    };
+
+    for (&(signal, _), previous) in SESSION_SIGNALS.iter().zip(previous.iter()) {
+        unsafe { libc::sigaction(signal, previous, std::ptr::null_mut()) };
+    }
+    SESSION_CHILD.store(0, Ordering::SeqCst);
+
+    result
+}
+
+// Block the session signals for the current thread and return the previous mask
+fn block_session_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        let mut old_mask: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for &(signal, _) in SESSION_SIGNALS.iter() {
+            libc::sigaddset(&mut set, signal);
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut old_mask);
+        old_mask
+    }
+}
+
+fn cloexec_pipe() -> io::Result<(c_int, c_int)> {
+    let mut fds = [0 as c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for fd in fds.iter() {
+        unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    Ok((fds[0], fds[1]))
+}
+
+// The step in which the child of `Client::run_session` failed
+#[derive(Clone, Copy)]
+enum ChildError {
+    Credentials = 1,
+    Exec = 2,
+}
+
+// Send the failed step and errno to the parent and exit
+fn report_child_error(fd: c_int, step: ChildError, errno: Option<c_int>) -> ! {
+    let errno = errno.unwrap_or(0);
+    let mut msg = [0u8; 5];
+    msg[0] = step as u8;
+    msg[1..].copy_from_slice(&errno.to_ne_bytes());
+    unsafe {
+        libc::write(fd, msg.as_ptr() as *const c_void, msg.len());
+        libc::_exit(127)
+    }
+}
+
+// Read the error reported by the child, if any
+fn read_child_error(fd: c_int) -> Option<PamReturnCode> {
+    let mut msg = [0u8; 5];
+    let len = loop {
+        let len = unsafe { libc::read(fd, msg.as_mut_ptr() as *mut c_void, msg.len()) };
+        if len >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            break len;
+        }
+    };
+    if len != msg.len() as isize {
+        return None;
+    }
+
+    let mut errno = [0u8; 4];
+    errno.copy_from_slice(&msg[1..]);
+    let errno = i32::from_ne_bytes(errno);
+    Some(match msg[0] {
+        step if step == ChildError::Credentials as u8 => PamReturnCode::Cred_Err,
+        _ if errno == libc::EACCES || errno == libc::EPERM => PamReturnCode::Perm_Denied,
+        _ => PamReturnCode::System_Err,
+    })
+}
+
+// Data passed to PAM as `appdata_ptr` of the conversation and the fail delay callback
+struct AppData<C> {
+    conversation: C,
+    fail_delay: Option<Box<FailDelayFn>>,
+    // Used to answer blind prompts if PAM doesn't allow setting the tokens as items
+    authtok: Option<Zeroizing<String>>,
+    old_authtok: Option<Zeroizing<String>>,
+    token_phase: TokenPhase,
+}
+
+// The PAM function currently running, which determines the prompts the tokens answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenPhase {
+    Idle,
+    Authenticate,
+    ChangeAuthtok,
+}
+
+type FailDelayFn = dyn FnMut(PamReturnCode, Duration) + Send;
+type FailDelayFnPtr = extern "C" fn(c_int, c_uint, *mut c_void);
+
+impl<C> AppData<C> {
+    // The preset token answering `msg`, if any
+    fn preset_answer(&self, msg: &conv::Message) -> Option<Result<CString, conv::ConvError>> {
+        if msg.style != PamMessageStyle::Prompt_Echo_Off {
+            return None;
+        }
+        let token = match self.token_phase {
+            TokenPhase::Idle => None,
+            TokenPhase::Authenticate => self.authtok.as_ref(),
+            TokenPhase::ChangeAuthtok => match conv::asks_for_old_authtok(msg.text) {
+                Some(true) => self.old_authtok.as_ref(),
+                _ => self.authtok.as_ref(),
+            },
+        }?;
+        // Reserve the nul byte up front, so the token is never copied by reallocating. The
+        // answer is wiped by `conv::converse` once PAM has its own copy.
+        let mut answer = Vec::with_capacity(token.len() + 1);
+        answer.extend_from_slice(token.as_bytes());
+        Some(CString::new(answer).map_err(|_| conv::ConvError::Failure))
+    }
+}
+
+// PAM only reaches this via `conv::converse`, which calls `converse`, so the preset tokens
+// are only handled there and the other methods simply forward
+impl<C: conv::Conversation> conv::Conversation for AppData<C> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, conv::ConvError> {
+        self.conversation.prompt_echo(msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, conv::ConvError> {
+        self.conversation.prompt_blind(msg)
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.conversation.info(msg)
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.conversation.error(msg)
+    }
+    fn converse(
+        &mut self,
+        msgs: &[conv::Message],
+    ) -> Result<Vec<Option<CString>>, conv::ConvError> {
+        if self.authtok.is_none() && self.old_authtok.is_none() {
+            return self.conversation.converse(msgs);
+        }
+
+        // Answer blind prompts with the preset tokens and pass the rest on in one batch
+        let mut responses = Vec::with_capacity(msgs.len());
+        let mut forwarded = Vec::new();
+        for (i, msg) in msgs.iter().enumerate() {
+            match self.preset_answer(msg) {
+                Some(answer) => responses.push(Some(answer?)),
+                None => {
+                    responses.push(None);
+                    forwarded.push(i);
+                }
+            }
+        }
+        if !forwarded.is_empty() {
+            let rest: Vec<_> = forwarded.iter().map(|&i| msgs[i]).collect();
+            let answers = self.conversation.converse(&rest)?;
+            if answers.len() != rest.len() {
+                return Err(conv::ConvError::Failure);
+            }
+            for (i, answer) in forwarded.into_iter().zip(answers) {
+                responses[i] = answer;
+            }
+        }
+        Ok(responses)
+    }
+}
+
+// Called by PAM with the `appdata_ptr` of the conversation instead of sleeping itself
+extern "C" fn fail_delay<C>(status: c_int, usec: c_uint, appdata_ptr: *mut c_void) {
+    let data = unsafe { &mut *(appdata_ptr as *mut AppData<C>) };
+    if let Some(ref mut callback) = data.fail_delay {
+        callback(status.into(), Duration::from_micros(usec.into()));
+    }
+}
+
+// Set a string item, PAM copies it so `value` only has to outlive the call
+fn set_str_item(handle: &mut PamHandle, item_type: PamItemType, value: &str) -> PamResult<()> {
+    // PAM would interpret the string as e.g. a `pam_conv` struct for other items
+    match item_type {
+        PamItemType::Service
+        | PamItemType::User
+        | PamItemType::TTY
+        | PamItemType::RHost
+        | PamItemType::RUser
+        | PamItemType::User_Prompt
+        | PamItemType::XDisplay => {}
+        _ => return Err(PamReturnCode::Bad_Item.into()),
+    }
+    let value = CString::new(value).map_err(|_| PamError(PamReturnCode::Buf_Err))?;
+    set_item(handle, item_type, unsafe {
+        &*(value.as_ptr() as *const c_void)
+    })
+}
+
+// Unset an item, e.g. so modules prompt for `PAM_USER` again
+fn clear_item(handle: &mut PamHandle, item_type: PamItemType) -> PamResult<()> {
+    let code = unsafe { ffi::pam_set_item(handle.as_ptr(), item_type as c_int, std::ptr::null()) };
+    match PamReturnCode::from(code) {
+        PamReturnCode::Success => Ok(()),
+        code => Err(code.into()),
+    }
+}
+
+impl<C: conv::Conversation> Drop for Client<C> {
     fn drop(&mut self) {
-        if self.has_open_session && self.close_on_drop {
-            close_session(self.handle, false);
+        if !self.has_ended {
+            let _ = self.teardown(self.drop_policy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn preset_tokens_answer_prompts_of_their_phase() {
+        let mut data = AppData {
+            conversation: conv::PasswordConv::new(),
+            fail_delay: None,
+            authtok: Some(Zeroizing::new(String::from("new"))),
+            old_authtok: Some(Zeroizing::new(String::from("old"))),
+            token_phase: TokenPhase::Idle,
+        };
+        let answer = |data: &AppData<_>, text: &str| {
+            let text = CString::new(text).unwrap();
+            let msg = conv::Message {
+                style: PamMessageStyle::Prompt_Echo_Off,
+                text: &text,
+            };
+            data.preset_answer(&msg)
+                .map(|answer| answer.unwrap().into_string().unwrap())
+        };
+
+        assert_eq!(answer(&data, "Password: "), None);
+
+        data.token_phase = TokenPhase::Authenticate;
+        assert_eq!(answer(&data, "Password: ").as_deref(), Some("new"));
+
+        data.token_phase = TokenPhase::ChangeAuthtok;
+        assert_eq!(answer(&data, "Current password: ").as_deref(), Some("old"));
+        assert_eq!(answer(&data, "New password: ").as_deref(), Some("new"));
+        data.authtok = None;
+        assert_eq!(answer(&data, "New password: "), None);
+    }
+
+    #[test]
+    fn retry_policy_retries_failed_attempts_only() {
+        let cases = [
+            (PamReturnCode::Auth_Err, true, true),
+            (PamReturnCode::Cred_Insufficient, true, true),
+            (PamReturnCode::Authinfo_Unavail, true, true),
+            (PamReturnCode::User_Unknown, true, false),
+            (PamReturnCode::MaxTries, false, false),
+            (PamReturnCode::Abort, false, false),
+            (PamReturnCode::Conv_Err, false, false),
+        ];
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.max_tries(), 3);
+        assert_eq!(RetryPolicy::new(0).max_tries(), 1);
+        let no_user_unknown = RetryPolicy::default().retry_user_unknown(false);
+        for &(code, retryable, retryable_without_user_unknown) in cases.iter() {
+            assert_eq!(policy.is_retryable(code), retryable, "{}", code);
+            assert_eq!(
+                no_user_unknown.is_retryable(code),
+                retryable_without_user_unknown,
+                "{}",
+                code
+            );
         }
-        let code = setcred(self.handle, PamFlag::Delete_Cred);
-        end(self.handle, code);
     }
 }
@@ -0,0 +1,182 @@
+//! Client configuration loaded via serde
+use std::{ffi::CStr, os::raw::c_char, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ClientBuilder, DropPolicy, EnvPolicy, PamItemType, RetryPolicy};
+
+/// Configuration of a `Client`, e.g. loaded from a TOML or JSON file
+///
+/// All fields except `service` are optional:
+///
+/// ```no_run
+/// use pam::{ClientConfig, ConversationType, TtyConv};
+///
+/// let config: ClientConfig = toml::from_str(r#"
+///     service = "login"
+///     detect_tty = true
+///     silent = true
+///     env_policy = "Both"
+///     max_tries = 3
+///     conversation = "Tty"
+/// "#).unwrap();
+///
+/// assert_eq!(config.conversation, ConversationType::Tty);
+/// let client = config.builder()
+///     .build(TtyConv::new())
+///     .expect("Failed to init PAM client.");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    /// The PAM service name
+    pub service: String,
+    /// The initial user, if it is known in advance
+    pub user: Option<String>,
+    /// Directory to read the service configuration from instead of `/etc/pam.d`
+    pub confdir: Option<PathBuf>,
+    /// The value of `PAM_TTY`
+    pub tty: Option<String>,
+    /// Set `PAM_TTY` to the terminal connected to stdin (if any) if `tty` is not set
+    #[serde(default)]
+    pub detect_tty: bool,
+    /// The value of `PAM_RHOST`
+    pub rhost: Option<String>,
+    /// The value of `PAM_RUSER`
+    pub ruser: Option<String>,
+    /// Pass `PAM_SILENT` to all PAM functions
+    #[serde(default)]
+    pub silent: bool,
+    /// Pass `PAM_DISALLOW_NULL_AUTHTOK` when authenticating and checking the account
+    #[serde(default)]
+    pub disallow_null_authtok: bool,
+    /// Where to set the common session variables
+    #[serde(default)]
+    pub env_policy: EnvPolicy,
+    /// What to do with the transaction when the client is dropped
+    #[serde(default)]
+    pub drop_policy: DropPolicy,
+    /// Number of authentication attempts before giving up (3 by default, at least 1), see
+    /// `ClientConfig::retry_policy`
+    #[serde(default = "default_max_tries")]
+    pub max_tries: u32,
+    /// The conversation handler the application should use
+    #[serde(default)]
+    pub conversation: ConversationType,
+}
+
+/// The kind of conversation handler configured via `ClientConfig`
+///
+/// Conversation handlers usually need state from the application (e.g. credentials or
+/// channels), so the application creates the handler matching this type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConversationType {
+    /// Interactive conversation on the controlling terminal via `TtyConv` (default)
+    Tty,
+    /// Non-interactive conversation via `PasswordConv`
+    Password,
+    /// Conversation driven by another thread via `ChannelConv`
+    Channel,
+}
+
+impl Default for ConversationType {
+    fn default() -> Self {
+        ConversationType::Tty
+    }
+}
+
+fn default_max_tries() -> u32 {
+    3
+}
+
+impl ClientConfig {
+    /// Create a `ClientBuilder` with the settings of this configuration
+    pub fn builder(&self) -> ClientBuilder {
+        let mut builder = ClientBuilder::new(&self.service)
+            .silent(self.silent)
+            .disallow_null_authtok(self.disallow_null_authtok)
+            .env_policy(self.env_policy)
+            .drop_policy(self.drop_policy);
+        if let Some(ref user) = self.user {
+            builder = builder.user(user);
+        }
+        if let Some(ref confdir) = self.confdir {
+            builder = builder.confdir(confdir);
+        }
+
+        let tty = match self.tty {
+            Some(ref tty) => Some(tty.clone()),
+            None if self.detect_tty => stdin_tty(),
+            None => None,
+        };
+        let items = [
+            (PamItemType::TTY, tty.as_ref()),
+            (PamItemType::RHost, self.rhost.as_ref()),
+            (PamItemType::RUser, self.ruser.as_ref()),
+        ];
+        for (item_type, value) in items.iter() {
+            if let Some(value) = value {
+                builder = builder.item(*item_type, value);
+            }
+        }
+        builder
+    }
+
+    /// The `RetryPolicy` for `Client::authenticate_with_retries` with `max_tries` attempts
+    ///
+    /// If a `user` is configured, it is not prompted again on retries.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::new(self.max_tries).reprompt_user(self.user.is_none())
+    }
+}
+
+// The name of the terminal connected to stdin, if any
+fn stdin_tty() -> Option<String> {
+    let mut buf = [0 as c_char; 256];
+    if unsafe { libc::ttyname_r(libc::STDIN_FILENO, buf.as_mut_ptr(), buf.len()) } != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    name.to_str().ok().map(String::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config_defaults_missing_optional_fields() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            service = "login"
+            rhost = "example.org"
+            drop_policy = "KeepSession"
+            conversation = "Channel"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.service, "login");
+        assert_eq!(config.rhost.as_deref(), Some("example.org"));
+        assert_eq!(config.drop_policy, DropPolicy::KeepSession);
+        assert_eq!(config.conversation, ConversationType::Channel);
+        assert_eq!(config.max_tries, 3);
+        assert!(!config.silent);
+        assert_eq!(config.env_policy, EnvPolicy::default());
+    }
+
+    #[test]
+    fn config_rejects_unknown_fields_and_missing_service() {
+        assert!(toml::from_str::<ClientConfig>("service = \"login\"\nunknown = true").is_err());
+        assert!(toml::from_str::<ClientConfig>("silent = true").is_err());
+    }
+
+    #[test]
+    fn pam_flag_is_deserialized_by_name() {
+        #[derive(Deserialize)]
+        struct Entry {
+            flag: crate::PamFlag,
+        }
+        let entry: Entry = toml::from_str("flag = \"Silent\"").unwrap();
+        assert_eq!(entry.flag, crate::PamFlag::Silent);
+    }
+}
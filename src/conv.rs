@@ -1,10 +1,54 @@
-use libc::{c_int, c_void, calloc, free, size_t, strdup};
+use libc::{c_int, c_void, calloc, size_t, strdup};
 
 use std::ffi::{CStr, CString};
-use std::mem;
+use std::{mem, ptr};
 
 use crate::{ffi::pam_conv, PamMessage, PamMessageStyle, PamResponse, PamReturnCode};
 
+/// Error returned by a `Conversation` handler
+///
+/// Each variant is passed back to PAM as a different return code, so modules and the
+/// application can distinguish e.g. a user aborting the conversation from a failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConvError {
+    /// The user aborted the conversation (e.g. by pressing Ctrl-C), returns `Abort`
+    Abort,
+    /// The conversation failed (e.g. due to a timeout or an unknown prompt), returns `Conv_Err`
+    Failure,
+    /// A buffer for the response could not be allocated, returns `Buf_Err`
+    Buffer,
+    /// The response is not available yet, returns `Conv_Again`
+    ///
+    /// This is meant for event-driven applications and requires the calling function
+    /// to be called again once the response is available.
+    Again,
+}
+
+impl From<ConvError> for PamReturnCode {
+    fn from(err: ConvError) -> PamReturnCode {
+        match err {
+            ConvError::Abort => PamReturnCode::Abort,
+            ConvError::Failure => PamReturnCode::Conv_Err,
+            ConvError::Buffer => PamReturnCode::Buf_Err,
+            ConvError::Again => PamReturnCode::Conv_Again,
+        }
+    }
+}
+
+impl std::fmt::Display for ConvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let msg = match self {
+            ConvError::Abort => "conversation aborted",
+            ConvError::Failure => "conversation failed",
+            ConvError::Buffer => "failed to allocate response",
+            ConvError::Again => "response not available yet",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for ConvError {}
+
 /// A trait representing the PAM authentification conversation
 ///
 /// PAM authentification is done as a conversation mechanism, in which PAM
@@ -15,21 +59,90 @@ use crate::{ffi::pam_conv, PamMessage, PamMessageStyle, PamResponse, PamReturnCo
 /// This is the trait to implement if you want to customize the conversation with
 /// PAM. If you just want a simple login/password authentication, you can use the
 /// `PasswordConv` implementation provided by this crate.
+///
+/// Returning an error from a prompt fails the whole conversation, the `ConvError`
+/// determines the return code passed back to PAM.
 pub trait Conversation {
     /// PAM requests a value that should be echoed to the user as they type it
     ///
     /// This would typically be the username. The exact question is provided as the
     /// `msg` argument if you wish to display it to your user.
-    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()>;
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ConvError>;
     /// PAM requests a value that should be typed blindly by the user
     ///
     /// This would typically be the password. The exact question is provided as the
     /// `msg` argument if you wish to display it to your user.
-    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()>;
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ConvError>;
     /// This is an informational message from PAM
     fn info(&mut self, msg: &CStr);
     /// This is an error message from PAM
+    ///
+    /// Error messages are informational (e.g. "Password will expire in 3 days") and do
+    /// not fail the conversation. Wrap the handler in a `StrictConv` to change this.
     fn error(&mut self, msg: &CStr);
+
+    /// PAM sent a batch of messages in a single conversation call
+    ///
+    /// All messages of one call belong together (e.g. an informational message followed
+    /// by the prompts for the username and password), so a graphical UI can display them
+    /// as a single form. The returned `Vec` must contain one entry per message: the
+    /// response to a prompt or `None` for informational and error messages.
+    ///
+    /// The default implementation calls the other methods of this trait for each message.
+    fn converse(&mut self, msgs: &[Message]) -> Result<Vec<Option<CString>>, ConvError> {
+        msgs.iter()
+            .map(|msg| match msg.style {
+                PamMessageStyle::Prompt_Echo_On => self.prompt_echo(msg.text).map(Some),
+                PamMessageStyle::Prompt_Echo_Off => self.prompt_blind(msg.text).map(Some),
+                PamMessageStyle::Text_Info => {
+                    self.info(msg.text);
+                    Ok(None)
+                }
+                PamMessageStyle::Error_Msg => {
+                    self.error(msg.text);
+                    Ok(None)
+                }
+            })
+            .collect()
+    }
+}
+
+/// A single message sent by PAM during the conversation
+#[derive(Debug, Clone, Copy)]
+pub struct Message<'a> {
+    /// The style of the message, i.e. whether it is a prompt or informational
+    pub style: PamMessageStyle,
+    /// The text of the message
+    pub text: &'a CStr,
+}
+
+impl Message<'_> {
+    /// Whether PAM expects a response to this message
+    pub fn is_prompt(&self) -> bool {
+        matches!(
+            self.style,
+            PamMessageStyle::Prompt_Echo_On | PamMessageStyle::Prompt_Echo_Off
+        )
+    }
+}
+
+// Allows e.g. `Client<Box<dyn Conversation>>` to switch between handlers of different types
+impl<C: Conversation + ?Sized> Conversation for Box<C> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        (**self).prompt_echo(msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        (**self).prompt_blind(msg)
+    }
+    fn info(&mut self, msg: &CStr) {
+        (**self).info(msg)
+    }
+    fn error(&mut self, msg: &CStr) {
+        (**self).error(msg)
+    }
+    fn converse(&mut self, msgs: &[Message]) -> Result<Vec<Option<CString>>, ConvError> {
+        (**self).converse(msgs)
+    }
 }
 
 /// A minimalistic conversation handler, that uses given login and password
@@ -43,7 +156,7 @@ pub struct PasswordConv {
 
 impl PasswordConv {
     /// Create a new `PasswordConv` handler
-    pub(crate) fn new() -> PasswordConv {
+    pub fn new() -> PasswordConv {
         PasswordConv {
             login: String::new(),
             passwd: String::new(),
@@ -57,12 +170,18 @@ impl PasswordConv {
     }
 }
 
+impl Default for PasswordConv {
+    fn default() -> Self {
+        PasswordConv::new()
+    }
+}
+
 impl Conversation for PasswordConv {
-    fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
-        CString::new(self.login.clone()).map_err(|_| ())
+    fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ConvError> {
+        CString::new(self.login.clone()).map_err(|_| ConvError::Failure)
     }
-    fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
-        CString::new(self.passwd.clone()).map_err(|_| ())
+    fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ConvError> {
+        CString::new(self.passwd.clone()).map_err(|_| ConvError::Failure)
     }
     fn info(&mut self, _msg: &CStr) {}
     fn error(&mut self, msg: &CStr) {
@@ -70,6 +189,240 @@ impl Conversation for PasswordConv {
     }
 }
 
+/// A conversation handler for changing a password non-interactively
+///
+/// Modules like pam_unix ask for the current password (`PAM_OLDAUTHTOK`) first and then
+/// for the new password (`PAM_AUTHTOK`) and its confirmation. This handler answers
+/// prompts mentioning the new password or its confirmation with the new password and
+/// prompts mentioning the current or old password with the old one. Other blind prompts
+/// are answered by order: the first one with the old password, all others with the new one.
+///
+/// Error messages (e.g. rejections by pam_pwquality) are collected and can be retrieved
+/// via `PasswordChangeConv::errors` after `Client::change_authentication_token` returned.
+pub struct PasswordChangeConv {
+    login: String,
+    old_passwd: String,
+    new_passwd: String,
+    prompted: bool,
+    errors: Vec<String>,
+}
+
+impl PasswordChangeConv {
+    /// Create a new `PasswordChangeConv` handler
+    pub fn new() -> PasswordChangeConv {
+        PasswordChangeConv {
+            login: String::new(),
+            old_passwd: String::new(),
+            new_passwd: String::new(),
+            prompted: false,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Set the credentials that this handler will provide to PAM
+    ///
+    /// This also clears any previously collected error messages.
+    pub fn set_credentials<U, V, W>(&mut self, login: U, old_password: V, new_password: W)
+    where
+        U: Into<String>,
+        V: Into<String>,
+        W: Into<String>,
+    {
+        self.login = login.into();
+        self.old_passwd = old_password.into();
+        self.new_passwd = new_password.into();
+        self.prompted = false;
+        self.errors.clear();
+    }
+
+    /// The error messages sent by PAM, e.g. why a new password was rejected
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+}
+
+impl Default for PasswordChangeConv {
+    fn default() -> Self {
+        PasswordChangeConv::new()
+    }
+}
+
+impl Conversation for PasswordChangeConv {
+    fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ConvError> {
+        CString::new(self.login.clone()).map_err(|_| ConvError::Failure)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        // PAM_OLDAUTHTOK is always requested before PAM_AUTHTOK
+        let wants_old = asks_for_old_authtok(msg).unwrap_or(!self.prompted);
+        self.prompted = true;
+
+        let passwd = if wants_old {
+            &self.old_passwd
+        } else {
+            &self.new_passwd
+        };
+        CString::new(passwd.clone()).map_err(|_| ConvError::Failure)
+    }
+    fn info(&mut self, _msg: &CStr) {}
+    fn error(&mut self, msg: &CStr) {
+        self.errors.push(msg.to_string_lossy().into_owned());
+    }
+}
+
+// Guess whether a blind prompt asks for the old or the new password, `None` if unclear
+pub(crate) fn asks_for_old_authtok(msg: &CStr) -> Option<bool> {
+    let prompt = msg.to_string_lossy().to_lowercase();
+    if ["new", "retype", "again", "repeat"]
+        .iter()
+        .any(|word| prompt.contains(word))
+    {
+        Some(false)
+    } else if prompt.contains("current") || prompt.contains("old") {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+/// A conversation handler wrapper which fails the conversation on error messages
+///
+/// By PAM spec error messages are informational, so they are only passed to the
+/// handler. Previous versions of this crate failed the conversation with `Conv_Err`
+/// after passing an error message to the handler, which this wrapper restores.
+pub struct StrictConv<C: Conversation> {
+    inner: C,
+}
+
+impl<C: Conversation> StrictConv<C> {
+    /// Wrap `inner` in a strict conversation handler
+    pub fn new(inner: C) -> StrictConv<C> {
+        StrictConv { inner }
+    }
+
+    /// Immutable access to the wrapped handler
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Mutable access to the wrapped handler
+    pub fn inner_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    /// Unwrap the wrapped handler
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Conversation> Conversation for StrictConv<C> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        self.inner.prompt_echo(msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        self.inner.prompt_blind(msg)
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.inner.info(msg)
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.inner.error(msg)
+    }
+    fn converse(&mut self, msgs: &[Message]) -> Result<Vec<Option<CString>>, ConvError> {
+        let first_error = msgs
+            .iter()
+            .position(|msg| msg.style == PamMessageStyle::Error_Msg);
+        match first_error {
+            // Pass on the messages up to the error, but fail the conversation afterwards
+            Some(i) => self
+                .inner
+                .converse(&msgs[..=i])
+                .and(Err(ConvError::Failure)),
+            None => self.inner.converse(msgs),
+        }
+    }
+}
+
+type MessageFn = Box<dyn for<'a> FnMut(Message<'a>) -> Option<CString>>;
+
+/// A conversation handler calling a closure for every message
+///
+/// The closure receives each message and returns the response to prompts. Returning
+/// `None` for a prompt fails the conversation (with `ConvError::Failure`), the return value
+/// for informational and error messages is ignored.
+///
+/// ```no_run
+/// use std::ffi::CString;
+/// use pam::{Client, FnConv, PamMessageStyle};
+///
+/// let conv = FnConv::new(|msg| match msg.style {
+///     PamMessageStyle::Prompt_Echo_On => CString::new("login").ok(),
+///     PamMessageStyle::Prompt_Echo_Off => CString::new("password").ok(),
+///     _ => {
+///         println!("{}", msg.text.to_string_lossy());
+///         None
+///     }
+/// });
+/// let mut client = Client::with_conversation("system-auth", conv)
+///     .expect("Failed to init PAM client.");
+/// ```
+pub struct FnConv {
+    f: MessageFn,
+}
+
+impl FnConv {
+    /// Create a new `FnConv` handler calling `f` for every message
+    pub fn new<F>(f: F) -> FnConv
+    where
+        F: for<'a> FnMut(Message<'a>) -> Option<CString> + 'static,
+    {
+        FnConv { f: Box::new(f) }
+    }
+
+    /// Create a handler answering visible prompts with `login` and blind prompts with `password`
+    pub fn credentials<U: Into<String>, V: Into<String>>(login: U, password: V) -> FnConv {
+        let login = CString::new(login.into()).ok();
+        let password = CString::new(password.into()).ok();
+        FnConv::new(move |msg| match msg.style {
+            PamMessageStyle::Prompt_Echo_On => login.clone(),
+            PamMessageStyle::Prompt_Echo_Off => password.clone(),
+            _ => None,
+        })
+    }
+
+    /// Create a handler answering blind prompts with `secret` and failing on visible ones
+    ///
+    /// This is useful if the user is already known to PAM (e.g. passed to `start`).
+    pub fn secret<V: Into<String>>(secret: V) -> FnConv {
+        let secret = CString::new(secret.into()).ok();
+        FnConv::new(move |msg| match msg.style {
+            PamMessageStyle::Prompt_Echo_Off => secret.clone(),
+            _ => None,
+        })
+    }
+
+    fn call(&mut self, style: PamMessageStyle, text: &CStr) -> Option<CString> {
+        (self.f)(Message { style, text })
+    }
+}
+
+impl Conversation for FnConv {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        self.call(PamMessageStyle::Prompt_Echo_On, msg)
+            .ok_or(ConvError::Failure)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        self.call(PamMessageStyle::Prompt_Echo_Off, msg)
+            .ok_or(ConvError::Failure)
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.call(PamMessageStyle::Text_Info, msg);
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.call(PamMessageStyle::Error_Msg, msg);
+    }
+}
+
 pub(crate) fn into_pam_conv<C: Conversation>(conv: &mut C) -> pam_conv {
     pam_conv {
         conv: Some(converse::<C>),
@@ -84,58 +437,175 @@ pub(crate) unsafe extern "C" fn converse<C: Conversation>(
     out_resp: *mut *mut PamResponse,
     appdata_ptr: *mut c_void,
 ) -> c_int {
+    if num_msg <= 0 {
+        return PamReturnCode::Conv_Err as c_int;
+    }
+
+    // collect typed messages
+    // FIXME: check this
+    let msgs: Vec<Message> = (0..num_msg as isize)
+        .map(|i| {
+            let m: &PamMessage = &**msg.offset(i);
+            Message {
+                style: PamMessageStyle::from(m.msg_style),
+                text: CStr::from_ptr(m.msg),
+            }
+        })
+        .collect();
+
+    let handler = &mut *(appdata_ptr as *mut C);
+    let responses = match handler.converse(&msgs) {
+        Ok(responses) => responses,
+        Err(err) => return PamReturnCode::from(err) as c_int,
+    };
+    let code = if responses.len() == msgs.len() {
+        copy_responses(&responses, out_resp)
+    } else {
+        PamReturnCode::Conv_Err
+    };
+    // Responses are usually secrets, so don't leave copies behind
+    responses.into_iter().flatten().for_each(wipe);
+    code as c_int
+}
+
+// Pass the responses to PAM, which takes ownership of the allocated memory
+unsafe fn copy_responses(
+    responses: &[Option<CString>],
+    out_resp: *mut *mut PamResponse,
+) -> PamReturnCode {
     // allocate space for responses
     let resp =
-        calloc(num_msg as usize, mem::size_of::<PamResponse>() as size_t) as *mut PamResponse;
+        calloc(responses.len(), mem::size_of::<PamResponse>() as size_t) as *mut PamResponse;
     if resp.is_null() {
-        return PamReturnCode::Buf_Err as c_int;
+        return PamReturnCode::Buf_Err;
     }
 
-    let handler = &mut *(appdata_ptr as *mut C);
+    for (i, response) in responses.iter().enumerate() {
+        if let Some(response) = response {
+            let r: &mut PamResponse = &mut *(resp.add(i));
+            r.resp = strdup(response.as_ptr());
+        }
+    }
 
-    let mut result: PamReturnCode = PamReturnCode::Success;
-    for i in 0..num_msg as isize {
-        // get indexed values
-        // FIXME: check this
-        let m: &mut PamMessage = &mut *(*(msg.offset(i)) as *mut PamMessage);
-        let r: &mut PamResponse = &mut *(resp.offset(i));
-
-        let msg = CStr::from_ptr(m.msg);
-        // match on msg_style
-        match PamMessageStyle::from(m.msg_style) {
-            PamMessageStyle::Prompt_Echo_On => {
-                if let Ok(handler_response) = handler.prompt_echo(msg) {
-                    r.resp = strdup(handler_response.as_ptr());
-                } else {
-                    result = PamReturnCode::Conv_Err;
-                }
-            }
-            PamMessageStyle::Prompt_Echo_Off => {
-                if let Ok(handler_response) = handler.prompt_blind(msg) {
-                    r.resp = strdup(handler_response.as_ptr());
-                } else {
-                    result = PamReturnCode::Conv_Err;
-                }
-            }
-            PamMessageStyle::Text_Info => {
-                handler.info(msg);
-            }
-            PamMessageStyle::Error_Msg => {
-                handler.error(msg);
-                result = PamReturnCode::Conv_Err;
-            }
+    *out_resp = resp;
+    PamReturnCode::Success
+}
+
+fn wipe(response: CString) {
+    let mut bytes = response.into_bytes();
+    for byte in bytes.iter_mut() {
+        // Volatile, so the compiler can't optimize the writes to a dying buffer away
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConvError, Conversation, Message, PasswordChangeConv, StrictConv};
+    use crate::{PamMessageStyle, PamReturnCode};
+    use std::ffi::{CStr, CString};
+
+    // Records the text of every message and answers prompts with it
+    #[derive(Default)]
+    struct EchoConv {
+        seen: Vec<String>,
+    }
+
+    impl EchoConv {
+        fn record(&mut self, msg: &CStr) -> CString {
+            self.seen.push(msg.to_str().unwrap().to_owned());
+            msg.to_owned()
+        }
+    }
+
+    impl Conversation for EchoConv {
+        fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+            Ok(self.record(msg))
+        }
+        fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+            Ok(self.record(msg))
         }
-        if result != PamReturnCode::Success {
-            break;
+        fn info(&mut self, msg: &CStr) {
+            self.record(msg);
+        }
+        fn error(&mut self, msg: &CStr) {
+            self.record(msg);
         }
     }
 
-    // free allocated memory if an error occured
-    if result != PamReturnCode::Success {
-        free(resp as *mut c_void);
-    } else {
-        *out_resp = resp;
+    fn messages(texts: &[CString]) -> Vec<Message<'_>> {
+        let styles = [
+            PamMessageStyle::Text_Info,
+            PamMessageStyle::Error_Msg,
+            PamMessageStyle::Prompt_Echo_Off,
+        ];
+        styles
+            .iter()
+            .zip(texts)
+            .map(|(style, text)| Message {
+                style: *style,
+                text,
+            })
+            .collect()
     }
 
-    result as c_int
+    fn prompt(conv: &mut PasswordChangeConv, msg: &str) -> String {
+        let msg = CString::new(msg).unwrap();
+        conv.prompt_blind(&msg).unwrap().into_string().unwrap()
+    }
+
+    #[test]
+    fn password_change_conv_answers_by_prompt() {
+        let mut conv = PasswordChangeConv::new();
+        conv.set_credentials("user", "old", "new");
+        assert_eq!(prompt(&mut conv, "Current password: "), "old");
+        assert_eq!(prompt(&mut conv, "New password: "), "new");
+        assert_eq!(prompt(&mut conv, "Retype new password: "), "new");
+
+        conv.set_credentials("user", "old", "new");
+        assert_eq!(prompt(&mut conv, "Passwort: "), "old");
+        assert_eq!(prompt(&mut conv, "Neues Passwort: "), "new");
+        assert_eq!(prompt(&mut conv, "Passwort wiederholen: "), "new");
+    }
+
+    #[test]
+    fn conv_errors_map_to_distinct_return_codes() {
+        let cases = [
+            (ConvError::Abort, PamReturnCode::Abort),
+            (ConvError::Failure, PamReturnCode::Conv_Err),
+            (ConvError::Buffer, PamReturnCode::Buf_Err),
+            (ConvError::Again, PamReturnCode::Conv_Again),
+        ];
+        for &(err, code) in cases.iter() {
+            assert_eq!(PamReturnCode::from(err), code, "{}", err);
+        }
+    }
+
+    #[test]
+    fn default_converse_treats_errors_as_non_fatal() {
+        let texts: Vec<_> = ["info", "error", "Password: "]
+            .iter()
+            .map(|text| CString::new(*text).unwrap())
+            .collect();
+        let mut conv = EchoConv::default();
+        let responses = conv.converse(&messages(&texts)).unwrap();
+        assert_eq!(responses, vec![None, None, Some(texts[2].clone())]);
+        assert_eq!(conv.seen, ["info", "error", "Password: "]);
+    }
+
+    #[test]
+    fn strict_conv_fails_at_first_error() {
+        let texts: Vec<_> = ["info", "error", "Password: "]
+            .iter()
+            .map(|text| CString::new(*text).unwrap())
+            .collect();
+        let mut conv = StrictConv::new(EchoConv::default());
+        assert_eq!(conv.converse(&messages(&texts)), Err(ConvError::Failure));
+        // The messages up to the error are still shown, but the prompt is not
+        assert_eq!(conv.inner().seen, ["info", "error"]);
+
+        let mut conv = StrictConv::new(EchoConv::default());
+        let responses = conv.converse(&messages(&texts)[2..]).unwrap();
+        assert_eq!(responses, vec![Some(texts[2].clone())]);
+    }
 }
@@ -8,6 +8,7 @@ use pam_macros::pam_enum;
 
 /// The Linux-PAM return values
 #[pam_enum]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PamReturnCode {
     /// System error
     System_Err,
@@ -119,6 +120,7 @@ impl std::fmt::Display for PamReturnCode {
 
 /// The Linux-PAM flags
 #[pam_enum]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PamFlag {
     /// Default value, if no specific flags should be passed
     None = 0,
@@ -177,6 +179,7 @@ impl std::fmt::Display for PamFlag {
 /// Please check the spec which are allowed for use by applications
 /// and which are only allowed for use by modules.
 #[pam_enum]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PamItemType {
     /// The service name
     Service,
@@ -226,6 +229,7 @@ impl std::fmt::Display for PamItemType {
 
 /// The Linux-PAM message styles
 #[pam_enum]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PamMessageStyle {
     Prompt_Echo_On,
     Prompt_Echo_Off,
@@ -3,7 +3,9 @@ use memchr::memchr;
 
 use std::vec::IntoIter;
 use std::ffi::{CStr, OsString};
+use std::process::Command;
 
+/// A copy of the PAM environment as returned by `getenvlist`
 pub struct PamEnvList {
     inner: IntoIter<(OsString, OsString)>
 }
@@ -23,9 +25,9 @@ impl PamEnvList {
     pub(crate) fn from_ptr(ptr: *const *const c_char) -> PamEnvList {
         let mut result = Vec::new();
 
-        unsafe {
-            let mut current = ptr;
-            if !current.is_null() {
+        if !ptr.is_null() {
+            unsafe {
+                let mut current = ptr;
                 while !(*current).is_null() {
                     if let Some(key_value) = parse_env_line(CStr::from_ptr(*current).to_bytes()) {
                         result.push(key_value);
@@ -33,13 +35,19 @@ impl PamEnvList {
                     current = current.add(1);
                 }
             }
+            drop_env_list(ptr);
         }
 
-        drop_env_list(ptr);
-        return PamEnvList { inner: result.into_iter() };
+        PamEnvList { inner: result.into_iter() }
     }
-}
 
+    /// Add all variables of this list to the environment of `command`
+    ///
+    /// Unlike iterating the list, this does not require the variables to be valid UTF-8.
+    pub fn apply_to(self, command: &mut Command) {
+        command.envs(self.inner);
+    }
+}
 
 fn parse_env_line(input: &[u8]) -> Option<(OsString, OsString)> {
     // Strategy (copied from glibc): Variable name and value are separated
@@ -51,7 +59,7 @@ fn parse_env_line(input: &[u8]) -> Option<(OsString, OsString)> {
     if input.is_empty() {
         return None;
     }
-    let pos = memchr(b'=', input).map(|p| p + 1);
+    let pos = memchr(b'=', &input[1..]).map(|p| p + 1);
     pos.map(|p| {
         (
             OsStringExt::from_vec(input[..p].to_vec()),
@@ -68,10 +76,31 @@ fn drop_env_list(ptr: *const *const c_char) {
 #[cfg(not(target_os = "linux"))]
 fn drop_env_list(ptr: *const *const c_char) {
     // FIXME: verify this
-    let mut cur = *ptr;
-    while !ptr.is_null() {
-        unsafe { free(ptr) };
-        ptr = ptr.add(1);
+    unsafe {
+        let mut current = ptr;
+        while !(*current).is_null() {
+            libc::free(*current as *mut libc::c_void);
+            current = current.add(1);
+        }
+        libc::free(ptr as *mut libc::c_void);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_env_line;
+
+    #[test]
+    fn parse_env_line_splits_at_first_equals() {
+        let (key, value) = parse_env_line(b"KRB5CCNAME=FILE:/tmp/krb5cc_1000").unwrap();
+        assert_eq!(key, "KRB5CCNAME");
+        assert_eq!(value, "FILE:/tmp/krb5cc_1000");
+
+        let (key, value) = parse_env_line(b"=FOO=bar").unwrap();
+        assert_eq!(key, "=FOO");
+        assert_eq!(value, "bar");
+
+        assert!(parse_env_line(b"MALFORMED").is_none());
+        assert!(parse_env_line(b"").is_none());
     }
-    unsafe { free(ptr) };
 }
@@ -19,34 +19,60 @@ mod appl {
     use crate::{ffi, PamFlag, PamHandle, PamResult, PamReturnCode};
 
     use std::ffi::CString;
-    use libc::c_int;
+    #[cfg(all(target_os = "linux", feature = "confdir"))]
+    use std::{os::unix::ffi::OsStrExt, path::Path};
+    use libc::{c_char, c_int};
 
     /// Create the PAM context and initiate the PAM transaction
     ///
     /// This needs to be called by an application to obtain a `PamHandle` which
     /// contains any transaction state.
     #[inline]
-    pub fn start<'a>(
+    pub fn start(
         service: &str,
         user: Option<&str>,
         conversation: &ffi::pam_conv,
-    ) -> PamResult<&'a mut PamHandle> {
+    ) -> PamResult<PamHandle> {
+        start_with(service, user, |service, user, handle| unsafe {
+            ffi::pam_start(service, user, conversation, handle)
+        })
+    }
+
+    /// Like `start`, but read the service configuration from `confdir` instead of
+    /// `/etc/pam.d`
+    ///
+    /// This requires Linux-PAM 1.4 or newer, so it is only available with the `confdir`
+    /// feature.
+    #[cfg(all(target_os = "linux", feature = "confdir"))]
+    #[inline]
+    pub fn start_confdir(
+        service: &str,
+        user: Option<&str>,
+        conversation: &ffi::pam_conv,
+        confdir: &Path,
+    ) -> PamResult<PamHandle> {
+        let confdir = match CString::new(confdir.as_os_str().as_bytes()) {
+            Ok(confdir) => confdir,
+            Err(_) => return super::buffer_error(),
+        };
+        start_with(service, user, |service, user, handle| unsafe {
+            ffi::pam_start_confdir(service, user, conversation, confdir.as_ptr(), handle)
+        })
+    }
+
+    fn start_with<F>(service: &str, user: Option<&str>, start: F) -> PamResult<PamHandle>
+    where
+        F: FnOnce(*const c_char, *const c_char, &mut *mut ffi::pam_handle_t) -> c_int,
+    {
         if let Ok(service) = CString::new(service) {
             // Only service is required -> initialize handle
-            let mut handle: *mut PamHandle = std::ptr::null_mut();
+            let mut handle: *mut ffi::pam_handle_t = std::ptr::null_mut();
 
-            let user_ptr = super::try_str_option_to_ptr(user)?;
-            match unsafe { ffi::pam_start(service.as_ptr(), user_ptr, conversation, &mut handle) }
-                .into()
-            {
-                // Reborrow is safe, because we check for null before
-                PamReturnCode::Success => {
-                    assert!(
-                        !handle.is_null(),
-                        "Got PAM_SUCESS from pam_start but handle is null!"
-                    );
-                    Ok(unsafe { &mut *handle })
-                }
+            let user = super::try_str_option_to_cstring(user)?;
+            match start(service.as_ptr(), super::option_as_ptr(&user), &mut handle).into() {
+                // Taking ownership is safe, because nobody else knows the handle
+                PamReturnCode::Success => Ok(unsafe { PamHandle::from_raw(handle) }
+                    .expect("Got PAM_SUCESS from pam_start but handle is null!")),
                 err => Err(err.into()),
             }
         } else {
@@ -57,11 +83,20 @@ mod appl {
 
     /// Terminate the PAM transaction
     ///
-    /// This function has to be called last in the PAM context.
+    /// This function has to be called last in the PAM context and consumes the handle
+    /// (dropping a `PamHandle` ends the transaction as well). If `data_silent` is set,
+    /// `PAM_DATA_SILENT` is passed to the cleanup functions of the modules. This should be
+    /// done by a forked child, so the modules only free their memory without tearing down
+    /// resources shared with the parent process.
     #[inline]
-    pub fn end(handle: &mut PamHandle, status: PamReturnCode) -> PamReturnCode {
-        // FIXME: Add PAM_DATA_SILENT argument?
-        unsafe { ffi::pam_end(handle, status as c_int) }.into()
+    pub fn end(handle: PamHandle, status: PamReturnCode, data_silent: bool) -> PamReturnCode {
+        let mut status = status as c_int;
+        if data_silent {
+            status |= ffi::PAM_DATA_SILENT as c_int;
+        }
+        // Don't run `Drop`, which would end the transaction a second time
+        let handle = std::mem::ManuallyDrop::new(handle);
+        unsafe { ffi::pam_end(handle.as_ptr(), status) }.into()
     }
 
     /// Authenticate the user via the `Conversation` passed to `start`
@@ -69,7 +104,7 @@ mod appl {
     /// Valid `PamFlag`s: Silent, Disallow_Null_AuthTok
     #[inline]
     pub fn authenticate(handle: &mut PamHandle, flags: PamFlag) -> PamReturnCode {
-        unsafe { ffi::pam_authenticate(handle, flags as c_int) }.into()
+        unsafe { ffi::pam_authenticate(handle.as_ptr(), flags as c_int) }.into()
     }
 
     /// Modify the credentials of the user associated with the PAM transaction
@@ -80,7 +115,7 @@ mod appl {
     /// Valid `PamFlag`s: Silent, {Establish,Delete,Reinitialize,Refresh}_Cred
     #[inline]
     pub fn setcred(handle: &mut PamHandle, flags: PamFlag) -> PamReturnCode {
-        unsafe { ffi::pam_setcred(handle, flags as c_int) }.into()
+        unsafe { ffi::pam_setcred(handle.as_ptr(), flags as c_int) }.into()
     }
 
     /// Determine if the user's account is valid
@@ -90,21 +125,25 @@ mod appl {
     /// Valid `PamFlag`s: Silent, Disallow_Null_AuthTok
     #[inline]
     pub fn acct_mgmt(handle: &mut PamHandle, flags: PamFlag) -> PamReturnCode {
-        unsafe { ffi::pam_acct_mgmt(handle, flags as c_int) }.into()
+        unsafe { ffi::pam_acct_mgmt(handle.as_ptr(), flags as c_int) }.into()
     }
 
     /// Set up a user session for a previously authenticated user
+    ///
+    /// If `silent` is set, `PAM_SILENT` is passed so modules don't send messages.
     #[inline]
     pub fn open_session(handle: &mut PamHandle, silent: bool) -> PamReturnCode {
-        let flag = silent as c_int;
-        unsafe { ffi::pam_open_session(handle, flag) }.into()
+        let flag = if silent { PamFlag::Silent as c_int } else { 0 };
+        unsafe { ffi::pam_open_session(handle.as_ptr(), flag) }.into()
     }
 
     /// Indicate that an authenticated user session has ended
+    ///
+    /// If `silent` is set, `PAM_SILENT` is passed so modules don't send messages.
     #[inline]
     pub fn close_session(handle: &mut PamHandle, silent: bool) -> PamReturnCode {
-        let flag = silent as c_int;
-        unsafe { ffi::pam_close_session(handle, flag) }.into()
+        let flag = if silent { PamFlag::Silent as c_int } else { 0 };
+        unsafe { ffi::pam_close_session(handle.as_ptr(), flag) }.into()
     }
 
     /// Change the authentication token for the user associated with the PAM
@@ -113,7 +152,7 @@ mod appl {
     /// Valid `PamFlag`s: Silent, Change_Expired_AuthTok
     #[inline]
     pub fn chauthtok(handle: &mut PamHandle, flags: PamFlag) -> PamReturnCode {
-        unsafe { ffi::pam_chauthtok(handle, flags as c_int) }.into()
+        unsafe { ffi::pam_chauthtok(handle.as_ptr(), flags as c_int) }.into()
     }
 }
 /* ------------------------ <security/pam_appl.h> -------------------------- */
@@ -122,7 +161,8 @@ mod appl {
 mod types {
     use crate::{env, ffi, PamHandle, PamItemType, PamResult, PamReturnCode};
 
-    use std::ffi::{CStr, CString};
+    use std::ffi::{CStr, CString, OsStr};
+    use std::os::unix::ffi::OsStrExt;
     use libc::{c_char, c_int, c_void};
 
     /// Update PAM information of type `item_type` in the associated PAM transaction
@@ -132,7 +172,7 @@ mod types {
         item_type: PamItemType,
         item: &c_void,
     ) -> PamResult<()> {
-        match unsafe { ffi::pam_set_item(handle, item_type as c_int, item) }.into() {
+        match unsafe { ffi::pam_set_item(handle.as_ptr(), item_type as c_int, item) }.into() {
             PamReturnCode::Success => Ok(()),
             err => Err(err.into()),
         }
@@ -142,7 +182,9 @@ mod types {
     #[inline]
     pub fn get_item<'a>(handle: &PamHandle, item_type: PamItemType) -> PamResult<&'a c_void> {
         let mut item_ptr: *const c_void = std::ptr::null();
-        match unsafe { ffi::pam_get_item(handle, item_type as c_int, &mut item_ptr) }.into() {
+        match unsafe { ffi::pam_get_item(handle.as_ptr(), item_type as c_int, &mut item_ptr) }
+            .into()
+        {
             PamReturnCode::Success => {
                 assert!(
                     !item_ptr.is_null(),
@@ -159,16 +201,16 @@ mod types {
     #[inline]
     pub fn strerror(handle: &mut PamHandle, errnum: PamReturnCode) -> &str {
         // We don't match here, as man says this function always returns a pointer to a string
-        unsafe { CStr::from_ptr(ffi::pam_strerror(handle, errnum as c_int)) }
+        unsafe { CStr::from_ptr(ffi::pam_strerror(handle.as_ptr(), errnum as c_int)) }
             .to_str()
             .expect("Got invalid UTF8 string from pam_strerror")
     }
 
     /// Add or change PAM environment variables associated with the PAM transaction
     #[inline]
-    pub fn putenv(handle: &mut PamHandle, name_value: &str) -> PamResult<()> {
-        if let Ok(name_value) = CString::new(name_value) {
-            match unsafe { ffi::pam_putenv(handle, name_value.as_ptr()) }.into() {
+    pub fn putenv<S: AsRef<OsStr>>(handle: &mut PamHandle, name_value: S) -> PamResult<()> {
+        if let Ok(name_value) = CString::new(name_value.as_ref().as_bytes()) {
+            match unsafe { ffi::pam_putenv(handle.as_ptr(), name_value.as_ptr()) }.into() {
                 PamReturnCode::Success => Ok(()),
                 err => Err(err.into()),
             }
@@ -183,7 +225,7 @@ mod types {
     pub fn getenv<'a>(handle: &'a mut PamHandle, name: &str) -> PamResult<Option<&'a str>> {
         if let Ok(name) = CString::new(name) {
             // Get environment variable
-            let env = unsafe { ffi::pam_getenv(handle, name.as_ptr()) };
+            let env = unsafe { ffi::pam_getenv(handle.as_ptr(), name.as_ptr()) };
             if !env.is_null() {
                 // Convert to rust &str
                 Ok(Some(
@@ -205,7 +247,7 @@ mod types {
     /// the PAM transaction
     #[inline]
     pub fn getenvlist(handle: &mut PamHandle) -> env::PamEnvList {
-        let ptr = unsafe { ffi::pam_getenvlist(handle) };
+        let ptr = unsafe { ffi::pam_getenvlist(handle.as_ptr()) };
         env::PamEnvList::from_ptr(ptr as *const *const c_char)
     }
 }
@@ -234,7 +276,7 @@ mod misc {
             .chain(Some(std::ptr::null()))
             .collect();
 
-        match unsafe { ffi::pam_misc_paste_env(handle, env_ptrs.as_ptr()) }.into() {
+        match unsafe { ffi::pam_misc_paste_env(handle.as_ptr(), env_ptrs.as_ptr()) }.into() {
             PamReturnCode::Success => Ok(()),
             err => Err(err.into()),
         }
@@ -258,6 +300,7 @@ mod misc {
     ) -> PamResult<()> {
         if let (Ok(name), Ok(value)) = (CString::new(name), CString::new(value)) {
             let flag = readonly as libc::c_int;
+            let handle = handle.as_ptr();
             match unsafe { ffi::pam_misc_setenv(handle, name.as_ptr(), value.as_ptr(), flag) }
                 .into()
             {
@@ -276,8 +319,10 @@ mod misc {
 mod modules {
     use crate::{ffi, PamHandle, PamResult, PamReturnCode};
 
+    use std::convert::TryFrom;
     use std::ffi::{CStr, CString};
-    use libc::{c_char, c_int, c_void};
+    use std::time::Duration;
+    use libc::{c_char, c_int, c_uint, c_void};
 
     /// Associate a pointer to an object with the given `module_data_name` in
     /// the current PAM context
@@ -287,9 +332,10 @@ mod modules {
         module_data_name: &str,
         data: &mut c_void,
         // FIXME: Remove bare ptrs from closure signature
-        cleanup: Option<unsafe extern "C" fn(*mut PamHandle, *mut c_void, c_int)>,
+        cleanup: Option<unsafe extern "C" fn(*mut ffi::pam_handle_t, *mut c_void, c_int)>,
     ) -> PamResult<()> {
         if let Ok(module_data_name) = CString::new(module_data_name) {
+            let handle = handle.as_ptr();
             match unsafe { ffi::pam_set_data(handle, module_data_name.as_ptr(), data, cleanup) }
                 .into()
             {
@@ -310,12 +356,11 @@ mod modules {
     /// Return the name of the user as specified via `start`
     #[inline]
     pub fn get_user<'a>(handle: &'a PamHandle, prompt: Option<&str>) -> PamResult<&'a str> {
-        // For some reason, bindgen marks the handl as mutable in pam_sys although man says const
-        let handle = handle as *const PamHandle as *mut PamHandle;
         let mut user_ptr: *const c_char = std::ptr::null();
-        let prompt_ptr = super::try_str_option_to_ptr(prompt)?;
+        let prompt = super::try_str_option_to_cstring(prompt)?;
+        let prompt_ptr = super::option_as_ptr(&prompt);
 
-        match unsafe { ffi::pam_get_user(handle, &mut user_ptr, prompt_ptr) }.into() {
+        match unsafe { ffi::pam_get_user(handle.as_ptr(), &mut user_ptr, prompt_ptr) }.into() {
             PamReturnCode::Success => {
                 assert!(
                     !user_ptr.is_null(),
@@ -328,6 +373,22 @@ mod modules {
             err => Err(err.into()),
         }
     }
+
+    /// Request a minimum delay before returning a failure to the application
+    ///
+    /// libpam uses the longest delay requested by any module of the stack, randomized by up
+    /// to 25%, and sleeps for it before e.g. `pam_authenticate` returns a failure. If the
+    /// application registered a `PAM_FAIL_DELAY` callback (see `Client::set_fail_delay`),
+    /// that callback receives the delay instead. The delay is capped at `u32::MAX`
+    /// microseconds.
+    #[inline]
+    pub fn fail_delay(handle: &PamHandle, delay: Duration) -> PamResult<()> {
+        let usec = u32::try_from(delay.as_micros()).unwrap_or(u32::MAX);
+        match unsafe { ffi::pam_fail_delay(handle.as_ptr(), usec as c_uint) }.into() {
+            PamReturnCode::Success => Ok(()),
+            err => Err(err.into()),
+        }
+    }
 }
 /* ----------------------- <security/pam_modules.h> ------------------------ */
 
@@ -336,13 +397,20 @@ fn buffer_error<T>() -> crate::PamResult<T> {
     Err(crate::PamReturnCode::Buf_Err.into())
 }
 
-fn try_str_option_to_ptr(opt: Option<&str>) -> crate::PamResult<*const libc::c_char> {
+fn try_str_option_to_cstring(
+    opt: Option<&str>,
+) -> crate::PamResult<Option<std::ffi::CString>> {
     match opt.map(std::ffi::CString::new) {
-        // Valid string given -> Return ptr of the converted CString
-        Some(Ok(content)) => Ok(content.as_ptr()),
-        // No string given -> Return null-ptr
-        None => Ok(std::ptr::null_mut()),
+        // Valid string given -> Return the converted CString
+        Some(Ok(content)) => Ok(Some(content)),
+        // No string given -> Return None
+        None => Ok(None),
         // Invalid string given -> Return BUF_ERR
         _ => Err(crate::PamReturnCode::Buf_Err.into()),
     }
 }
+
+// The pointer is only valid as long as `opt` is alive
+fn option_as_ptr(opt: &Option<std::ffi::CString>) -> *const libc::c_char {
+    opt.as_ref().map_or(std::ptr::null(), |content| content.as_ptr())
+}
@@ -1,6 +1,9 @@
 #![allow(non_camel_case_types)]
 // We want to pass PamHandles by ref as they are opaque
 #![allow(clippy::trivially_copy_pass_by_ref)]
+// libc's C types are aliases of `core::ffi` types on newer compilers, which clippy flags
+// as incompatible with our MSRV although libc supports older ones as well
+#![allow(clippy::incompatible_msrv)]
 
 //! Rustified API to the Linux-PAM authentication libary
 //!
@@ -11,27 +14,49 @@
 
 // Reexport pam_sys so downstream users don't need to depend on it
 pub use pam_sys as ffi;
+// Reexport the secret type taken by `Client::set_authtok` for the same reason
+#[cfg(feature = "client")]
+pub use zeroize::Zeroizing;
 
+mod channel;
+#[cfg(all(feature = "client", feature = "serde"))]
+mod config;
 mod conv;
 mod enums;
 mod env;
 mod functions;
+mod router;
+mod tty;
 mod types;
 
 pub use crate::{enums::*, functions::*, types::*};
 
+#[cfg(feature = "async")]
+pub mod async_client;
 #[cfg(feature = "client")]
 pub mod client;
 #[cfg(feature = "module")]
 pub mod module;
 
 pub use crate::{
-    conv::{Conversation, PasswordConv},
+    channel::{ChannelConv, Prompt},
+    conv::{
+        ConvError, Conversation, FnConv, Message, PasswordChangeConv, PasswordConv, StrictConv,
+    },
     enums::*,
+    env::PamEnvList,
+    router::{Answer, PromptPattern, RoutingConv, SecretStore, UnmatchedPolicy},
+    tty::TtyConv,
 };
 
+#[cfg(feature = "async")]
+pub use async_client::AsyncClient;
 #[cfg(feature = "client")]
-pub use client::Client;
+pub use client::{
+    AccountStatus, AuthProgress, Client, ClientBuilder, DropPolicy, EnvPolicy, RetryPolicy,
+};
+#[cfg(all(feature = "client", feature = "serde"))]
+pub use config::{ClientConfig, ConversationType};
 
 #[cfg(feature = "module")]
 pub use module::PamModule;
@@ -2,9 +2,10 @@
 //!
 //! Inspired by anowell/pam-rs
 
-use crate::{PamHandle, PamReturnCode};
+use crate::{functions, PamHandle, PamResult, PamReturnCode};
 use std::ffi::CStr;
 use std::os::raw::c_uint;
+use std::time::Duration;
 
 // FIXME: Find a solution for the flags containing ORed integers
 #[allow(unused_variables)]
@@ -43,6 +44,16 @@ pub trait PamModule {
     }
 }
 
+impl PamHandle {
+    /// Request a minimum delay before a failure is returned to the application
+    ///
+    /// See `pam::fail_delay` for how the delay is applied and how applications can handle
+    /// it themselves.
+    pub fn fail_delay(&self, delay: Duration) -> PamResult<()> {
+        functions::fail_delay(self, delay)
+    }
+}
+
 #[macro_export]
 /// Export the given struct as a PAM module by wiring up the respective extern "C" functions
 macro_rules! export_pam_module {
@@ -50,8 +61,9 @@ macro_rules! export_pam_module {
         pub use _pam_module_::*;
         mod _pam_module_ {
             use std::ffi::CStr;
+            use std::mem::ManuallyDrop;
             use std::os::raw::{c_char, c_int, c_uint};
-            use $crate::{PamHandle, PamModule, PamReturnCode};
+            use $crate::{ffi::pam_handle_t, PamHandle, PamModule, PamReturnCode};
 
             fn convert_args<'a>(argc: c_int, argv: *const *const c_char) -> Vec<&'a CStr> {
                 (0..argc)
@@ -59,65 +71,73 @@ macro_rules! export_pam_module {
                     .collect()
             }
 
+            // The handle is borrowed from libpam, so it must not be ended on drop
+            fn call(
+                handle: *mut pam_handle_t,
+                flags: c_uint,
+                argc: c_int,
+                argv: *const *const c_char,
+                f: fn(&PamHandle, Vec<&CStr>, c_uint) -> PamReturnCode,
+            ) -> PamReturnCode {
+                match unsafe { PamHandle::from_raw(handle) } {
+                    Some(handle) => f(&ManuallyDrop::new(handle), convert_args(argc, argv), flags),
+                    None => PamReturnCode::System_Err,
+                }
+            }
+
             #[no_mangle]
             pub extern "C" fn pam_sm_acct_mgmt(
-                handle: &PamHandle,
+                handle: *mut pam_handle_t,
                 flags: c_uint,
                 argc: c_int,
                 argv: *const *const c_char,
             ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::account_management(handle, args, flags)
+                call(handle, flags, argc, argv, super::$struct::account_management)
             }
             #[no_mangle]
             pub extern "C" fn pam_sm_authenticate(
-                handle: &PamHandle,
+                handle: *mut pam_handle_t,
                 flags: c_uint,
                 argc: c_int,
                 argv: *const *const c_char,
             ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::authenticate(handle, args, flags)
+                call(handle, flags, argc, argv, super::$struct::authenticate)
             }
             #[no_mangle]
             pub extern "C" fn pam_sm_chauthtok(
-                handle: &PamHandle,
+                handle: *mut pam_handle_t,
                 flags: c_uint,
                 argc: c_int,
                 argv: *const *const c_char,
             ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::change_auth_token(handle, args, flags)
+                call(handle, flags, argc, argv, super::$struct::change_auth_token)
             }
             #[no_mangle]
             pub extern "C" fn pam_sm_close_session(
-                handle: &PamHandle,
+                handle: *mut pam_handle_t,
                 flags: c_uint,
                 argc: c_int,
                 argv: *const *const c_char,
             ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::close_session(handle, args, flags)
+                call(handle, flags, argc, argv, super::$struct::close_session)
             }
             #[no_mangle]
             pub extern "C" fn pam_sm_open_session(
-                handle: &PamHandle,
+                handle: *mut pam_handle_t,
                 flags: c_uint,
                 argc: c_int,
                 argv: *const *const c_char,
             ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::open_session(handle, args, flags)
+                call(handle, flags, argc, argv, super::$struct::open_session)
             }
             #[no_mangle]
             pub extern "C" fn pam_sm_setcred(
-                handle: &PamHandle,
+                handle: *mut pam_handle_t,
                 flags: c_uint,
                 argc: c_int,
                 argv: *const *const c_char,
             ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::set_credentials(handle, args, flags)
+                call(handle, flags, argc, argv, super::$struct::set_credentials)
             }
         }
     };
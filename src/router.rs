@@ -0,0 +1,232 @@
+//! Conversation handler routing prompts to different answer providers
+use std::ffi::{CStr, CString};
+
+use crate::{
+    conv::{ConvError, Conversation},
+    PamMessageStyle,
+};
+
+/// Pattern selecting the prompts a route of a `RoutingConv` answers
+pub enum PromptPattern {
+    /// Prompts whose text equals the given string
+    Exact(String),
+    /// Prompts whose text matches the given regular expression
+    #[cfg(feature = "regex")]
+    Regex(regex::Regex),
+    /// The n-th prompt (starting at 0) since the handler was created or reset
+    Ordinal(usize),
+    /// All prompts of the given message style
+    Style(PamMessageStyle),
+}
+
+impl PromptPattern {
+    fn matches(&self, style: PamMessageStyle, index: usize, msg: &CStr) -> bool {
+        match self {
+            PromptPattern::Exact(text) => msg.to_bytes() == text.as_bytes(),
+            #[cfg(feature = "regex")]
+            PromptPattern::Regex(regex) => regex.is_match(&msg.to_string_lossy()),
+            PromptPattern::Ordinal(n) => *n == index,
+            PromptPattern::Style(s) => *s == style,
+        }
+    }
+}
+
+/// A source of secrets (e.g. a keyring) which can answer prompts
+pub trait SecretStore {
+    /// Look up the answer to `prompt`, returning `None` if the store has none
+    fn lookup(&mut self, prompt: &CStr) -> Option<CString>;
+}
+
+type AnswerFn = Box<dyn FnMut(&CStr) -> Option<CString>>;
+
+/// Provider of the answer to the prompts matched by a route of a `RoutingConv`
+pub enum Answer {
+    /// Always answer with the given value
+    Static(CString),
+    /// Call the closure with the prompt, `None` fails the conversation
+    Fn(AnswerFn),
+    /// Look up the answer in a `SecretStore`, `None` fails the conversation
+    Store(Box<dyn SecretStore>),
+}
+
+impl Answer {
+    /// Create an `Answer::Fn` from the given closure
+    pub fn from_fn<F: FnMut(&CStr) -> Option<CString> + 'static>(f: F) -> Answer {
+        Answer::Fn(Box::new(f))
+    }
+
+    /// Create an `Answer::Store` from the given store
+    pub fn from_store<S: SecretStore + 'static>(store: S) -> Answer {
+        Answer::Store(Box::new(store))
+    }
+
+    fn answer(&mut self, msg: &CStr) -> Option<CString> {
+        match self {
+            Answer::Static(value) => Some(value.clone()),
+            Answer::Fn(f) => f(msg),
+            Answer::Store(store) => store.lookup(msg),
+        }
+    }
+}
+
+/// Policy for prompts which are not matched by any route of a `RoutingConv`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmatchedPolicy {
+    /// Fail the conversation (default)
+    Fail,
+    /// Answer with an empty string
+    Empty,
+}
+
+impl Default for UnmatchedPolicy {
+    fn default() -> Self {
+        UnmatchedPolicy::Fail
+    }
+}
+
+/// A conversation handler which routes prompts to answer providers
+///
+/// Each prompt is answered by the first route whose pattern matches it. This allows
+/// answering stacks with multiple factors (e.g. a password and a one-time password)
+/// without relying on the message style alone:
+///
+/// ```no_run
+/// use std::ffi::CString;
+/// use pam::{Answer, Client, PamMessageStyle, PromptPattern, RoutingConv};
+///
+/// let conv = RoutingConv::new()
+///     .route(PromptPattern::Exact("Verification code: ".into()), Answer::from_fn(|_| {
+///         CString::new("123456").ok()
+///     }))
+///     .route(
+///         PromptPattern::Style(PamMessageStyle::Prompt_Echo_On),
+///         Answer::Static(CString::new("login").unwrap()),
+///     )
+///     .route(
+///         PromptPattern::Style(PamMessageStyle::Prompt_Echo_Off),
+///         Answer::Static(CString::new("password").unwrap()),
+///     );
+/// let mut client = Client::with_conversation("system-auth", conv)
+///     .expect("Failed to init PAM client.");
+/// client.authenticate().expect("Authentication failed!");
+/// ```
+///
+/// Informational and error messages are collected and can be retrieved via
+/// `RoutingConv::messages`.
+#[derive(Default)]
+pub struct RoutingConv {
+    routes: Vec<(PromptPattern, Answer)>,
+    unmatched: UnmatchedPolicy,
+    prompts: usize,
+    messages: Vec<(PamMessageStyle, String)>,
+}
+
+impl RoutingConv {
+    /// Create a new `RoutingConv` without any routes
+    pub fn new() -> RoutingConv {
+        RoutingConv::default()
+    }
+
+    /// Answer prompts matching `pattern` with `answer`
+    ///
+    /// Routes are tried in the order they were added.
+    pub fn route(mut self, pattern: PromptPattern, answer: Answer) -> RoutingConv {
+        self.routes.push((pattern, answer));
+        self
+    }
+
+    /// Set the policy for prompts not matched by any route
+    pub fn unmatched(mut self, policy: UnmatchedPolicy) -> RoutingConv {
+        self.unmatched = policy;
+        self
+    }
+
+    /// The informational and error messages sent by PAM
+    pub fn messages(&self) -> &[(PamMessageStyle, String)] {
+        &self.messages
+    }
+
+    /// Reset the prompt counter used by `PromptPattern::Ordinal` and clear the messages
+    pub fn reset(&mut self) {
+        self.prompts = 0;
+        self.messages.clear();
+    }
+
+    fn answer(&mut self, style: PamMessageStyle, msg: &CStr) -> Result<CString, ConvError> {
+        let index = self.prompts;
+        self.prompts += 1;
+
+        match self
+            .routes
+            .iter_mut()
+            .find(|(pattern, _)| pattern.matches(style, index, msg))
+        {
+            Some((_, answer)) => answer.answer(msg).ok_or(ConvError::Failure),
+            None => match self.unmatched {
+                UnmatchedPolicy::Fail => Err(ConvError::Failure),
+                UnmatchedPolicy::Empty => Ok(CString::default()),
+            },
+        }
+    }
+}
+
+impl Conversation for RoutingConv {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        self.answer(PamMessageStyle::Prompt_Echo_On, msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        self.answer(PamMessageStyle::Prompt_Echo_Off, msg)
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.messages.push((
+            PamMessageStyle::Text_Info,
+            msg.to_string_lossy().into_owned(),
+        ));
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.messages.push((
+            PamMessageStyle::Error_Msg,
+            msg.to_string_lossy().into_owned(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cstr(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    #[test]
+    fn routing_conv_uses_first_matching_route() {
+        let mut conv = RoutingConv::new()
+            .route(PromptPattern::Ordinal(0), Answer::Static(cstr("first")))
+            .route(
+                PromptPattern::Exact("Verification code: ".into()),
+                Answer::Static(cstr("123456")),
+            )
+            .route(
+                PromptPattern::Style(PamMessageStyle::Prompt_Echo_Off),
+                Answer::from_fn(|msg| Some(cstr(&format!("answer to {}", msg.to_string_lossy())))),
+            );
+
+        assert_eq!(conv.prompt_echo(&cstr("login: ")).unwrap(), cstr("first"));
+        assert_eq!(
+            conv.prompt_blind(&cstr("Verification code: ")).unwrap(),
+            cstr("123456")
+        );
+        assert_eq!(
+            conv.prompt_blind(&cstr("Password: ")).unwrap(),
+            cstr("answer to Password: ")
+        );
+        assert!(conv.prompt_echo(&cstr("Favourite color? ")).is_err());
+
+        let mut conv = conv.unmatched(UnmatchedPolicy::Empty);
+        assert_eq!(
+            conv.prompt_echo(&cstr("Favourite color? ")).unwrap(),
+            cstr("")
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Interactive conversation handler using the controlling terminal
+use std::{
+    ffi::{CStr, CString},
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    mem::{self, MaybeUninit},
+    os::{raw::c_int, unix::io::AsRawFd},
+    ptr,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Mutex, MutexGuard, Once,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::conv::{ConvError, Conversation};
+
+/// A conversation handler interacting with the user on the controlling terminal
+///
+/// Prompts are written to and answers are read from `/dev/tty`, so this also works if
+/// stdin and stdout are redirected. While reading the answer to a blind prompt (e.g. a
+/// password), echo is disabled. The terminal state is restored afterwards, even if the
+/// thread panics or the process receives SIGHUP, SIGINT, SIGQUIT or SIGTERM.
+/// Informational and error messages are printed to the terminal as well.
+///
+/// If a timeout is set, a prompt which is not answered in time fails the conversation.
+/// Closing the input (Ctrl-D) aborts the conversation with `ConvError::Abort`.
+///
+/// Signals are not intercepted: after restoring the terminal, the signal is delivered
+/// with its previous disposition. With the default dispositions, pressing Ctrl-C thus
+/// terminates the process. Only if the application handles the signal itself and the
+/// read is interrupted, the conversation is aborted with `ConvError::Abort`. As there is
+/// only one terminal state to restore, blind prompts of concurrent handlers are answered
+/// one after the other.
+#[derive(Debug, Default, Clone)]
+pub struct TtyConv {
+    timeout: Option<Duration>,
+}
+
+impl TtyConv {
+    /// Create a new `TtyConv` handler without a timeout
+    pub fn new() -> TtyConv {
+        TtyConv::default()
+    }
+
+    /// Create a new `TtyConv` handler which waits at most `timeout` for each answer
+    pub fn with_timeout(timeout: Duration) -> TtyConv {
+        TtyConv {
+            timeout: Some(timeout),
+        }
+    }
+
+    /// Change the time to wait for each answer, `None` waits indefinitely
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn prompt(&self, msg: &CStr, echo: bool) -> io::Result<CString> {
+        let mut tty = open_tty()?;
+        tty.write_all(msg.to_bytes())?;
+        tty.flush()?;
+
+        let answer = if echo {
+            self.read_line(&mut tty)?
+        } else {
+            let _guard = EchoGuard::disable_echo(tty.as_raw_fd())?;
+            self.read_line(&mut tty)?
+        };
+        CString::new(answer).map_err(|_| io::ErrorKind::InvalidData.into())
+    }
+
+    fn read_line(&self, tty: &mut File) -> io::Result<Vec<u8>> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        loop {
+            if let Some(deadline) = deadline {
+                wait_readable(tty.as_raw_fd(), deadline)?;
+            }
+            match tty.read(&mut byte)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                _ if byte[0] == b'\n' => return Ok(line),
+                _ => line.push(byte[0]),
+            }
+        }
+    }
+
+    fn print(&self, msg: &CStr) {
+        if let Ok(mut tty) = open_tty() {
+            let _ = tty
+                .write_all(msg.to_bytes())
+                .and_then(|_| tty.write_all(b"\n"));
+        }
+    }
+}
+
+impl Conversation for TtyConv {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        self.prompt(msg, true).map_err(conv_error)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ConvError> {
+        self.prompt(msg, false).map_err(conv_error)
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.print(msg);
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.print(msg);
+    }
+}
+
+// An interrupted read and Ctrl-D abort the conversation, all other errors fail it
+fn conv_error(err: io::Error) -> ConvError {
+    match err.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::UnexpectedEof => ConvError::Abort,
+        _ => ConvError::Failure,
+    }
+}
+
+fn open_tty() -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).open("/dev/tty")
+}
+
+// Wait until `fd` is readable or `deadline` has passed
+fn wait_readable(fd: c_int, deadline: Instant) -> io::Result<()> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let timeout = remaining.as_millis().min(c_int::MAX as u128) as c_int;
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Err(io::ErrorKind::TimedOut.into()),
+        _ => Ok(()),
+    }
+}
+
+const RESTORE_SIGNALS: [c_int; 4] = [libc::SIGHUP, libc::SIGINT, libc::SIGQUIT, libc::SIGTERM];
+
+// State needed by the signal handler to restore the terminal. It is written before the
+// handler is installed and only read afterwards. Only the holder of `echo_lock()` may
+// install the handler, so concurrent prompts don't overwrite each other's state.
+static TTY_FD: AtomicI32 = AtomicI32::new(-1);
+static mut SAVED_TERMIOS: MaybeUninit<libc::termios> = MaybeUninit::uninit();
+static mut PREVIOUS_ACTIONS: MaybeUninit<[libc::sigaction; 4]> = MaybeUninit::uninit();
+
+// `Mutex::new` is only a `const fn` since Rust 1.63, but our MSRV is 1.60
+fn echo_lock() -> MutexGuard<'static, ()> {
+    static INIT: Once = Once::new();
+    static mut LOCK: MaybeUninit<Mutex<()>> = MaybeUninit::uninit();
+    let lock = unsafe {
+        INIT.call_once(|| {
+            (*ptr::addr_of_mut!(LOCK)).write(Mutex::new(()));
+        });
+        &*(ptr::addr_of!(LOCK) as *const Mutex<()>)
+    };
+    // The lock protects no data, so a panic while holding it leaves nothing inconsistent
+    lock.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+extern "C" fn restore_terminal(signal: c_int) {
+    let fd = TTY_FD.load(Ordering::SeqCst);
+    unsafe {
+        // The guard may already have restored the terminal, but the signal must still
+        // be delivered
+        if fd >= 0 {
+            libc::tcsetattr(
+                fd,
+                libc::TCSAFLUSH,
+                ptr::addr_of!(SAVED_TERMIOS) as *const _,
+            );
+        }
+        // Reinstall the previous disposition and deliver the signal again once we return
+        let previous = ptr::addr_of!(PREVIOUS_ACTIONS) as *const libc::sigaction;
+        if let Some(i) = RESTORE_SIGNALS.iter().position(|s| *s == signal) {
+            libc::sigaction(signal, previous.add(i), ptr::null_mut());
+        }
+        libc::raise(signal);
+    }
+}
+
+// Disables echo on a terminal until dropped
+struct EchoGuard {
+    fd: c_int,
+    termios: libc::termios,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl EchoGuard {
+    fn disable_echo(fd: c_int) -> io::Result<EchoGuard> {
+        // Lock before saving the terminal state, which another prompt may have changed
+        let lock = echo_lock();
+        let mut termios = MaybeUninit::uninit();
+        if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let termios = unsafe { termios.assume_init() };
+
+        unsafe {
+            (*ptr::addr_of_mut!(SAVED_TERMIOS)).write(termios);
+            TTY_FD.store(fd, Ordering::SeqCst);
+
+            let previous = ptr::addr_of_mut!(PREVIOUS_ACTIONS) as *mut libc::sigaction;
+            for (i, signal) in RESTORE_SIGNALS.iter().enumerate() {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction =
+                    restore_terminal as extern "C" fn(c_int) as libc::sighandler_t;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(*signal, &action, previous.add(i));
+            }
+        }
+        // Construct the guard now, so everything is restored if disabling echo fails
+        let guard = EchoGuard {
+            fd,
+            termios,
+            _lock: lock,
+        };
+
+        // Keep echoing the newline, so the cursor moves on after the answer
+        let mut no_echo = termios;
+        no_echo.c_lflag &= !libc::ECHO;
+        no_echo.c_lflag |= libc::ECHONL;
+        if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &no_echo) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(guard)
+    }
+}
+
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSAFLUSH, &self.termios);
+            TTY_FD.store(-1, Ordering::SeqCst);
+
+            let previous = ptr::addr_of!(PREVIOUS_ACTIONS) as *const libc::sigaction;
+            for (i, signal) in RESTORE_SIGNALS.iter().enumerate() {
+                libc::sigaction(*signal, previous.add(i), ptr::null_mut());
+            }
+        }
+    }
+}
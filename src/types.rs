@@ -1,7 +1,42 @@
+use std::ptr::NonNull;
+
 use crate::enums::PamReturnCode;
 
-/// Opaque PAM main structure. Used for nearly all application functions
-pub type PamHandle = pam_sys::pam_handle_t;
+/// Handle of a PAM transaction. Used for nearly all application functions
+///
+/// A `PamHandle` obtained from `start` owns the transaction and ends it via `pam_end`
+/// (with `PamReturnCode::Success`) when dropped. Use `end` to pass a different status.
+#[derive(Debug)]
+pub struct PamHandle(NonNull<pam_sys::pam_handle_t>);
+
+// SAFETY: libpam keeps no thread-local state for a transaction, so it can be moved to
+// another thread. It must not be used from multiple threads at once, hence no `Sync`.
+unsafe impl Send for PamHandle {}
+
+impl PamHandle {
+    /// Take ownership of a raw handle, returns `None` if `ptr` is null
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a valid handle returned by `pam_start` which is not ended by anyone
+    /// else. Modules receive a borrowed handle, which has to be wrapped in a
+    /// `std::mem::ManuallyDrop` so it is not ended on drop.
+    pub unsafe fn from_raw(ptr: *mut pam_sys::pam_handle_t) -> Option<PamHandle> {
+        NonNull::new(ptr).map(PamHandle)
+    }
+
+    /// Return the raw handle to pass to `pam_sys` functions
+    pub fn as_ptr(&self) -> *mut pam_sys::pam_handle_t {
+        self.0.as_ptr()
+    }
+}
+
+impl Drop for PamHandle {
+    fn drop(&mut self) {
+        unsafe { pam_sys::pam_end(self.as_ptr(), PamReturnCode::Success as libc::c_int) };
+    }
+}
+
 /// PAM message that is passed to modules
 pub type PamMessage = pam_sys::pam_message;
 /// PAM response returned by modules